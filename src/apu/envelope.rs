@@ -0,0 +1,113 @@
+//Volume envelope of NRx2, clocked at 64 Hz
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+}
+
+const VOLUME_MAX: u8 = 0x0F;
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.initial_volume << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increase = data & 0b00001000 > 0;
+        self.period = data & 0b00000111;
+    }
+
+    //The channel DAC is off when both the volume and the direction are 0
+    pub fn dac_enabled(&self) -> bool {
+        self.read() & 0xF8 != 0
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return;
+        }
+
+        self.timer = self.period;
+        if self.increase && self.volume < VOLUME_MAX {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triggered(nrx2: u8) -> Envelope {
+        let mut envelope = Envelope::new();
+        envelope.write(nrx2);
+        envelope.trigger();
+        envelope
+    }
+
+    fn volumes(envelope: &mut Envelope, clocks: usize) -> Vec<u8> {
+        (0..clocks)
+            .map(|_| {
+                envelope.clock();
+                envelope.volume()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn period_0_keeps_the_volume() {
+        let mut envelope = triggered(0xA0);
+        assert_eq!(envelope.volume(), 10);
+        assert_eq!(volumes(&mut envelope, 16), vec![10; 16]);
+    }
+
+    #[test]
+    fn volume_steps_every_period_and_stops_at_the_limits() {
+        let mut envelope = triggered(0x21);
+        assert_eq!(volumes(&mut envelope, 4), vec![1, 0, 0, 0]);
+
+        let mut envelope = triggered(0xDA);
+        assert_eq!(volumes(&mut envelope, 6), vec![13, 14, 14, 15, 15, 15]);
+    }
+
+    #[test]
+    fn dac_needs_a_volume_or_an_increase() {
+        assert!(!triggered(0x00).dac_enabled());
+        assert!(!triggered(0x07).dac_enabled());
+        assert!(triggered(0x08).dac_enabled());
+        assert!(triggered(0x10).dac_enabled());
+    }
+}
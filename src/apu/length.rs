@@ -0,0 +1,79 @@
+//Silences the channel once the counter reaches 0, clocked at 256 Hz
+pub struct LengthCounter {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, data: u8) {
+        self.counter = self.max - (data as u16 & (self.max - 1));
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    //Returns true when the counter expires and the channel must be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_after_the_loaded_length() {
+        let mut length = LengthCounter::new(64);
+        length.load(60);
+        length.set_enabled(true);
+
+        let expired: Vec<bool> = (0..6).map(|_| length.clock()).collect();
+        assert_eq!(expired, vec![false, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn only_counts_when_enabled() {
+        let mut length = LengthCounter::new(256);
+        length.load(0xFF);
+        assert!(!length.clock());
+
+        length.set_enabled(true);
+        assert!(length.clock());
+    }
+
+    #[test]
+    fn trigger_reloads_an_expired_counter() {
+        let mut length = LengthCounter::new(64);
+        length.set_enabled(true);
+        length.trigger();
+
+        assert_eq!((0..64).filter(|_| length.clock()).count(), 1);
+        assert!(!length.clock());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::apu::resampler::Resampler;
+
+//Routes the channels to the two outputs (NR51), applies master volume (NR50),
+//the high-pass filter and resamples to the host rate
+pub struct Mixer {
+    nr50: u8,
+    nr51: u8,
+
+    //Muted channels are left out of the mix but still recorded as stems
+    audible: [bool; 4],
+
+    //Left, right, then channels 1 to 4 before panning
+    accumulator: [f32; 6],
+    accumulated: u32,
+
+    //Feeds the audio sink
+    output: Option<Output>,
+    //Feeds recordings, at a fixed rate whatever the sink uses
+    capture: Option<Output>,
+}
+
+struct Output {
+    resampler: Resampler,
+    high_pass: [HighPass; 2],
+    resampled: Vec<[f32; 2]>,
+    samples: Vec<f32>,
+    stems: Option<Stems>,
+}
+
+//Each channel on its own, resampled by pairs
+struct Stems {
+    resamplers: [Resampler; 2],
+    high_pass: [HighPass; 4],
+    resampled: [Vec<[f32; 2]>; 2],
+    samples: Vec<[f32; 4]>,
+}
+
+//DC blocking capacitor of the DMG output stage
+struct HighPass {
+    capacitor: f32,
+    charge: f32,
+}
+
+const CPU_FREQUENCY: f64 = 4194304.0;
+
+//Channels are averaged over this many cycles before resampling
+const DECIMATION: u32 = 32;
+const DECIMATED_RATE: f64 = CPU_FREQUENCY / DECIMATION as f64;
+
+//Buffered samples are dropped past one second if nobody reads them
+const MAX_BUFFERED_SECONDS: usize = 1;
+
+//The 4 channels share the output range
+const CHANNEL_SCALE: f32 = 1.0 / 4.0;
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer {
+            nr50: 0,
+            nr51: 0,
+
+            audible: [true; 4],
+
+            accumulator: [0.0; 6],
+            accumulated: 0,
+
+            output: None,
+            capture: None,
+        }
+    }
+
+    //None disables sample generation
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.output = sample_rate.map(|rate| Output::new(rate, false));
+    }
+
+    //Second output for recordings, optionally with every channel isolated
+    pub fn set_capture(&mut self, capture: Option<(u32, bool)>) {
+        self.capture = capture.map(|(rate, stems)| Output::new(rate, stems));
+    }
+
+    pub fn set_audible(&mut self, audible: [bool; 4]) {
+        self.audible = audible;
+    }
+
+    pub fn read_nr50(&self) -> u8 {
+        self.nr50
+    }
+
+    pub fn read_nr51(&self) -> u8 {
+        self.nr51
+    }
+
+    pub fn write_nr50(&mut self, data: u8) {
+        self.nr50 = data;
+    }
+
+    pub fn write_nr51(&mut self, data: u8) {
+        self.nr51 = data;
+    }
+
+    //Analog outputs of the 4 channel DACs, None when a DAC is off
+    pub fn do_cycle(&mut self, channels: [Option<f32>; 4]) {
+        if self.output.is_none() && self.capture.is_none() {
+            return;
+        }
+
+        for (i, channel) in channels.iter().enumerate() {
+            let value = channel.unwrap_or(0.0);
+            self.accumulator[2 + i] += value;
+            if !self.audible[i] {
+                continue;
+            }
+
+            if self.nr51 & (0x10 << i) > 0 {
+                self.accumulator[0] += value;
+            }
+            if self.nr51 & (0x01 << i) > 0 {
+                self.accumulator[1] += value;
+            }
+        }
+
+        self.accumulated += 1;
+        if self.accumulated < DECIMATION {
+            return;
+        }
+
+        let left_volume = ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.nr50 & 0x07) as f32 + 1.0;
+        let scale = CHANNEL_SCALE / DECIMATION as f32;
+        let sample = [
+            self.accumulator[0] * left_volume / 8.0 * scale,
+            self.accumulator[1] * right_volume / 8.0 * scale,
+        ];
+        let mut stems = [0.0; 4];
+        for (stem, value) in stems.iter_mut().zip(&self.accumulator[2..]) {
+            *stem = value * scale;
+        }
+        self.accumulator = [0.0; 6];
+        self.accumulated = 0;
+
+        let dacs_on = channels.iter().any(Option::is_some);
+        if let Some(output) = self.output.as_mut() {
+            output.push(sample, stems, dacs_on);
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.push(sample, stems, dacs_on);
+        }
+    }
+
+    //Interleaved stereo samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        match self.output.as_mut() {
+            Some(output) => output.samples.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    //Stereo capture samples, and the isolated channels when enabled
+    pub fn take_capture(&mut self) -> (Vec<f32>, Vec<[f32; 4]>) {
+        match self.capture.as_mut() {
+            Some(capture) => {
+                let stems = match capture.stems.as_mut() {
+                    Some(stems) => stems.samples.drain(..).collect(),
+                    None => Vec::new(),
+                };
+                (capture.samples.drain(..).collect(), stems)
+            }
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    pub fn power_off(&mut self) {
+        self.nr50 = 0;
+        self.nr51 = 0;
+    }
+}
+
+impl Output {
+    fn new(sample_rate: u32, stems: bool) -> Self {
+        let resampler = || Resampler::new(DECIMATED_RATE, sample_rate as f64);
+        let high_pass = || HighPass::new(sample_rate);
+
+        Output {
+            resampler: resampler(),
+            high_pass: [high_pass(), high_pass()],
+            resampled: Vec::new(),
+            samples: Vec::with_capacity(sample_rate as usize * 2 * MAX_BUFFERED_SECONDS),
+            stems: if stems {
+                Some(Stems {
+                    resamplers: [resampler(), resampler()],
+                    high_pass: [high_pass(), high_pass(), high_pass(), high_pass()],
+                    resampled: [Vec::new(), Vec::new()],
+                    samples: Vec::with_capacity(sample_rate as usize * MAX_BUFFERED_SECONDS),
+                })
+            } else {
+                None
+            },
+        }
+    }
+
+    fn push(&mut self, sample: [f32; 2], channels: [f32; 4], dacs_on: bool) {
+        self.resampler.push(sample, &mut self.resampled);
+        for sample in self.resampled.drain(..) {
+            self.samples
+                .push(self.high_pass[0].apply(sample[0], dacs_on));
+            self.samples
+                .push(self.high_pass[1].apply(sample[1], dacs_on));
+        }
+
+        let capacity = self.samples.capacity();
+        if self.samples.len() >= capacity {
+            self.samples.drain(..capacity / 2);
+        }
+
+        if let Some(stems) = self.stems.as_mut() {
+            stems.push(channels, dacs_on);
+        }
+    }
+}
+
+impl Stems {
+    fn push(&mut self, channels: [f32; 4], dacs_on: bool) {
+        self.resamplers[0].push([channels[0], channels[1]], &mut self.resampled[0]);
+        self.resamplers[1].push([channels[2], channels[3]], &mut self.resampled[1]);
+
+        //Both resamplers run in lockstep and produce as many samples
+        let [first, second] = &mut self.resampled;
+        for (a, b) in first.drain(..).zip(second.drain(..)) {
+            let mut sample = [a[0], a[1], b[0], b[1]];
+            for (value, high_pass) in sample.iter_mut().zip(self.high_pass.iter_mut()) {
+                *value = high_pass.apply(*value, dacs_on);
+            }
+            self.samples.push(sample);
+        }
+
+        let capacity = self.samples.capacity();
+        if self.samples.len() >= capacity {
+            self.samples.drain(..capacity / 2);
+        }
+    }
+}
+
+impl HighPass {
+    fn new(sample_rate: u32) -> Self {
+        HighPass {
+            capacitor: 0.0,
+            charge: 0.999958f64.powf(CPU_FREQUENCY / sample_rate as f64) as f32,
+        }
+    }
+
+    fn apply(&mut self, input: f32, dacs_on: bool) -> f32 {
+        if !dacs_on {
+            return 0.0;
+        }
+
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    //Channel 1 at full level, the other DACs off
+    fn run(mixer: &mut Mixer, cycles: usize) -> Vec<f32> {
+        for _ in 0..cycles {
+            mixer.do_cycle([Some(1.0), None, None, None]);
+        }
+        mixer.take_samples()
+    }
+
+    fn panned(nr50: u8, nr51: u8) -> Mixer {
+        let mut mixer = Mixer::new();
+        mixer.set_sample_rate(Some(SAMPLE_RATE));
+        mixer.write_nr50(nr50);
+        mixer.write_nr51(nr51);
+        mixer
+    }
+
+    #[test]
+    fn produces_samples_at_the_sink_rate() {
+        let mut mixer = panned(0x77, 0xFF);
+        let samples = run(&mut mixer, CPU_FREQUENCY as usize / 10);
+
+        //Interleaved stereo, short of the resampler delay
+        let frames = samples.len() / 2;
+        let expected = SAMPLE_RATE as usize / 10;
+        assert!(
+            frames <= expected && frames > expected - 64,
+            "{} frames",
+            frames
+        );
+    }
+
+    #[test]
+    fn nr51_pans_the_channels() {
+        let mut mixer = panned(0x77, 0x01);
+        let samples = run(&mut mixer, CPU_FREQUENCY as usize / 100);
+        assert!(samples.chunks(2).all(|frame| frame[0] == 0.0));
+        assert!(samples.chunks(2).any(|frame| frame[1] > 0.0));
+
+        let mut mixer = panned(0x77, 0x10);
+        let samples = run(&mut mixer, CPU_FREQUENCY as usize / 100);
+        assert!(samples.chunks(2).any(|frame| frame[0] > 0.0));
+        assert!(samples.chunks(2).all(|frame| frame[1] == 0.0));
+    }
+
+    #[test]
+    fn nr50_scales_each_side() {
+        let mut mixer = panned(0x70, 0x11);
+        let samples = run(&mut mixer, CPU_FREQUENCY as usize / 100);
+        let peak = |side: usize| {
+            samples
+                .chunks(2)
+                .map(|frame| frame[side])
+                .fold(0.0f32, f32::max)
+        };
+        assert!((peak(0) / peak(1) - 8.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut high_pass = HighPass::new(SAMPLE_RATE);
+        assert_eq!(high_pass.apply(1.0, true), 1.0);
+        let mut output = 1.0;
+        for _ in 0..SAMPLE_RATE {
+            output = high_pass.apply(1.0, true);
+        }
+        assert!(output.abs() < 1e-3);
+
+        //Nothing comes out while every DAC is off
+        assert_eq!(high_pass.apply(1.0, false), 0.0);
+    }
+}
@@ -0,0 +1,397 @@
+mod envelope;
+mod length;
+mod mixer;
+mod noise;
+mod pulse;
+mod resampler;
+mod wav;
+mod wave;
+
+use std::io;
+use std::path::Path;
+
+use crate::audio::{AudioSink, NullSink};
+
+use self::mixer::Mixer;
+use self::noise::Noise;
+use self::pulse::Pulse;
+use self::wav::WavWriter;
+use self::wave::Wave;
+
+pub struct Apu {
+    ch1: Pulse,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+
+    mixer: Mixer,
+    sink: Box<dyn AudioSink>,
+    recording: Option<Recording>,
+    muted: [bool; 4],
+    solo: Option<Channel>,
+    power: bool,
+
+    frame_step: u8,
+    div_bit: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+}
+
+//What a channel is playing, for debug views
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelState {
+    pub enabled: bool,
+    //In Hz, the LFSR clock rate for the noise channel
+    pub frequency: f64,
+    //0 to 15
+    pub volume: u8,
+    //High part of the pulse channels waveform
+    pub duty: Option<f32>,
+    //False when muted or another channel is soloed
+    pub audible: bool,
+}
+
+//Stereo mix, and channels 1 to 4 on their own when recording stems
+struct Recording {
+    mix: WavWriter,
+    stems: Vec<WavWriter>,
+}
+
+//Recordings do not depend on the sink rate so runs can be compared
+const RECORDING_SAMPLE_RATE: u32 = 44100;
+
+//The frame sequencer steps on the falling edge of this DIV bit, 512 Hz
+const FRAME_SEQUENCER_DIV_BIT: u16 = 1 << 12;
+const FRAME_SEQUENCER_STEPS: u8 = 8;
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+
+            mixer: Mixer::new(),
+            sink: Box::new(NullSink),
+            recording: None,
+            muted: [false; 4],
+            solo: None,
+            power: false,
+
+            frame_step: 0,
+            div_bit: false,
+        }
+    }
+
+    pub fn do_cycle(&mut self, div: u16) {
+        self.ch1.do_cycle();
+        self.ch2.do_cycle();
+        self.ch3.do_cycle();
+        self.ch4.do_cycle();
+
+        let div_bit = div & FRAME_SEQUENCER_DIV_BIT > 0;
+        if self.power && self.div_bit && !div_bit {
+            self.clock_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        self.mixer.do_cycle([
+            dac(self.ch1.dac_enabled(), self.ch1.output()),
+            dac(self.ch2.dac_enabled(), self.ch2.output()),
+            dac(self.ch3.dac_enabled(), self.ch3.output()),
+            dac(self.ch4.dac_enabled(), self.ch4.output()),
+        ]);
+    }
+
+    //Samples are mixed at the rate the sink asks for, not at all for the null sink
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.mixer.set_sample_rate(sink.sample_rate());
+        self.sink = sink;
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+        self.update_audible();
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    //While a channel is soloed it is the only one heard, even when muted
+    pub fn set_solo(&mut self, solo: Option<Channel>) {
+        self.solo = solo;
+        self.update_audible();
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    pub fn channel_states(&self) -> [ChannelState; 4] {
+        let mut states = [
+            self.ch1.state(),
+            self.ch2.state(),
+            self.ch3.state(),
+            self.ch4.state(),
+        ];
+        for (state, &audible) in states.iter_mut().zip(self.audible().iter()) {
+            state.audible = audible;
+        }
+        states
+    }
+
+    fn audible(&self) -> [bool; 4] {
+        let mut audible = [false; 4];
+        for (i, &channel) in Channel::ALL.iter().enumerate() {
+            audible[i] = match self.solo {
+                Some(solo) => solo == channel,
+                None => !self.muted[i],
+            };
+        }
+        audible
+    }
+
+    fn update_audible(&mut self) {
+        let audible = self.audible();
+        self.mixer.set_audible(audible);
+    }
+
+    //Hands the samples of the frame to the sink and the recording
+    pub fn end_frame(&mut self) {
+        let samples = self.mixer.take_samples();
+        if !samples.is_empty() {
+            self.sink.write(&samples);
+        }
+
+        if let Some(recording) = self.recording.as_mut() {
+            let (mix, stems) = self.mixer.take_capture();
+            if let Err(e) = recording.write(&mix, &stems) {
+                println!("Recording stopped: {}", e);
+                self.stop_recording().ok();
+            }
+        }
+    }
+
+    //Stems go next to the mix, "song.wav" gives "song.ch1.wav" to "song.ch4.wav"
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        self.stop_recording()?;
+
+        let mix = WavWriter::create(path, 2, RECORDING_SAMPLE_RATE)?;
+        let mut stem_writers = Vec::new();
+        if stems {
+            for channel in 1..=4 {
+                let stem_path = path.with_extension(format!("ch{}.wav", channel));
+                stem_writers.push(WavWriter::create(&stem_path, 1, RECORDING_SAMPLE_RATE)?);
+            }
+        }
+
+        self.mixer.set_capture(Some((RECORDING_SAMPLE_RATE, stems)));
+        self.recording = Some(Recording {
+            mix,
+            stems: stem_writers,
+        });
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        self.mixer.set_capture(None);
+        match self.recording.take() {
+            Some(recording) => recording.finish(),
+            None => Ok(()),
+        }
+    }
+
+    //Length at 256 Hz, sweep at 128 Hz and envelopes at 64 Hz
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_step % 2 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.clock_envelope();
+            self.ch2.clock_envelope();
+            self.ch4.clock_envelope();
+        }
+
+        self.frame_step = (self.frame_step + 1) % FRAME_SEQUENCER_STEPS;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10...0xFF14 => self.ch1.read(addr - 0xFF10),
+            0xFF15...0xFF19 => self.ch2.read(addr - 0xFF15),
+            0xFF1A...0xFF1E => self.ch3.read(addr - 0xFF1A),
+            0xFF1F...0xFF23 => self.ch4.read(addr - 0xFF1F),
+            0xFF24 => self.mixer.read_nr50(),
+            0xFF25 => self.mixer.read_nr51(),
+            0xFF26 => {
+                (self.power as u8) << 7
+                    | 0x70
+                    | (self.ch4.is_enabled() as u8) << 3
+                    | (self.ch3.is_enabled() as u8) << 2
+                    | (self.ch2.is_enabled() as u8) << 1
+                    | self.ch1.is_enabled() as u8
+            }
+            0xFF30...0xFF3F => self.ch3.read_ram(addr - 0xFF30),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        //Powered off, only NR52, wave RAM and the length counters can be written
+        if !self.power {
+            match addr {
+                0xFF11 => self.ch1.write_length(data),
+                0xFF16 => self.ch2.write_length(data),
+                0xFF1B => self.ch3.write_length(data),
+                0xFF20 => self.ch4.write_length(data),
+                0xFF26 => self.write_power(data),
+                0xFF30...0xFF3F => self.ch3.write_ram(addr - 0xFF30, data),
+                _ => {}
+            }
+            return;
+        }
+
+        match addr {
+            0xFF10...0xFF14 => self.ch1.write(addr - 0xFF10, data),
+            0xFF15...0xFF19 => self.ch2.write(addr - 0xFF15, data),
+            0xFF1A...0xFF1E => self.ch3.write(addr - 0xFF1A, data),
+            0xFF1F...0xFF23 => self.ch4.write(addr - 0xFF1F, data),
+            0xFF24 => self.mixer.write_nr50(data),
+            0xFF25 => self.mixer.write_nr51(data),
+            0xFF26 => self.write_power(data),
+            0xFF30...0xFF3F => self.ch3.write_ram(addr - 0xFF30, data),
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, data: u8) {
+        let power = data & 0b10000000 > 0;
+        if self.power && !power {
+            self.ch1.power_off();
+            self.ch2.power_off();
+            self.ch3.power_off();
+            self.ch4.power_off();
+            self.mixer.power_off();
+        } else if !self.power && power {
+            self.frame_step = 0;
+        }
+        self.power = power;
+    }
+}
+
+//Channel DACs map 0 to 15 onto 1.0 to -1.0, a disabled DAC outputs nothing
+fn dac(enabled: bool, output: u8) -> Option<f32> {
+    if enabled {
+        Some(1.0 - output as f32 / 7.5)
+    } else {
+        None
+    }
+}
+
+impl Recording {
+    fn write(&mut self, mix: &[f32], stems: &[[f32; 4]]) -> io::Result<()> {
+        self.mix.write(mix)?;
+        for (channel, writer) in self.stems.iter_mut().enumerate() {
+            let samples: Vec<f32> = stems.iter().map(|sample| sample[channel]).collect();
+            writer.write(&samples)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        for writer in self.stems {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Channel 2 playing with one length clock left
+    fn apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x3F);
+        apu.write(0xFF19, 0xC0);
+        apu
+    }
+
+    fn ch2_enabled(apu: &Apu) -> bool {
+        apu.read(0xFF26) & 0b00000010 > 0
+    }
+
+    #[test]
+    fn frame_sequencer_steps_on_the_falling_edge_of_div_bit_12() {
+        let mut apu = apu();
+        for div in 0..=FRAME_SEQUENCER_DIV_BIT {
+            apu.do_cycle(div);
+        }
+        apu.do_cycle(2 * FRAME_SEQUENCER_DIV_BIT - 1);
+        assert!(ch2_enabled(&apu));
+
+        apu.do_cycle(2 * FRAME_SEQUENCER_DIV_BIT);
+        assert!(!ch2_enabled(&apu));
+    }
+
+    #[test]
+    fn frame_sequencer_is_stopped_while_powered_off() {
+        let mut apu = Apu::new();
+        apu.do_cycle(FRAME_SEQUENCER_DIV_BIT);
+        apu.do_cycle(0);
+        assert_eq!(apu.frame_step, 0);
+
+        apu.write(0xFF26, 0x80);
+        apu.do_cycle(FRAME_SEQUENCER_DIV_BIT);
+        apu.do_cycle(0);
+        assert_eq!(apu.frame_step, 1);
+    }
+
+    #[test]
+    fn power_off_clears_the_registers() {
+        let mut apu = apu();
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xF3);
+        apu.write(0xFF26, 0x00);
+
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF25), 0x00);
+        assert_eq!(apu.read(0xFF17), 0x00);
+        assert_eq!(apu.read(0xFF26), 0x70);
+
+        //Only NR52 is writable until power comes back
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x77);
+    }
+}
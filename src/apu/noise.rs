@@ -0,0 +1,222 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length::LengthCounter;
+use crate::apu::ChannelState;
+
+//Channel 4, pseudo random noise from a linear feedback shift register
+pub struct Noise {
+    enabled: bool,
+
+    clock_shift: u8,
+    width_7bit: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+const LENGTH_MAX: u16 = 64;
+const CPU_FREQUENCY: f64 = 4194304.0;
+
+//Shifts of 14 and 15 stop the LFSR
+const CLOCK_SHIFT_MAX: u8 = 13;
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            enabled: false,
+
+            clock_shift: 0,
+            width_7bit: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+
+            length: LengthCounter::new(LENGTH_MAX),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn do_cycle(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return;
+        }
+
+        self.timer = self.period();
+        if self.clock_shift > CLOCK_SHIFT_MAX {
+            return;
+        }
+
+        let xor = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | xor << 14;
+        if self.width_7bit {
+            self.lfsr = (self.lfsr & !(1 << 6)) | xor << 6;
+        }
+    }
+
+    //Digital output, 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 > 0 {
+            return 0;
+        }
+
+        self.envelope.volume()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    //The frequency is the LFSR clock rate
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled && self.clock_shift <= CLOCK_SHIFT_MAX,
+            frequency: CPU_FREQUENCY / self.period() as f64,
+            volume: self.envelope.volume(),
+            duty: None,
+            audible: true,
+        }
+    }
+
+    //Registers NR40 to NR44, NR40 does not exist
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 | 1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => self.clock_shift << 4 | (self.width_7bit as u8) << 3 | self.divisor_code,
+            _ => 0xBF | (self.length.is_enabled() as u8) << 6,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {}
+            1 => self.length.load(data & 0x3F),
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = data >> 4;
+                self.width_7bit = data & 0b00001000 > 0;
+                self.divisor_code = data & 0b00000111;
+            }
+            _ => {
+                self.length.set_enabled(data & 0b01000000 > 0);
+                if data & 0b10000000 > 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    pub fn write_length(&mut self, data: u8) {
+        self.length.load(data & 0x3F);
+    }
+
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(LENGTH_MAX));
+        *self = Noise::new();
+        self.length = length;
+        self.length.set_enabled(false);
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Full volume, the LFSR shifts every 8 cycles with divisor code 0 and shift 0
+    fn playing(nr43: u8) -> Noise {
+        let mut noise = Noise::new();
+        noise.write(2, 0xF0);
+        noise.write(3, nr43);
+        noise.write(4, 0x80);
+        noise
+    }
+
+    fn shift(noise: &mut Noise) {
+        for _ in 0..DIVISORS[0] {
+            noise.do_cycle();
+        }
+    }
+
+    #[test]
+    fn lfsr_15_bit_repeats_every_32767_shifts() {
+        let mut noise = playing(0x00);
+        let mut shifts = 0;
+        loop {
+            shift(&mut noise);
+            shifts += 1;
+            if noise.lfsr == 0x7FFF {
+                break;
+            }
+        }
+        assert_eq!(shifts, 32767);
+    }
+
+    #[test]
+    fn lfsr_7_bit_repeats_every_127_shifts() {
+        let mut noise = playing(0x08);
+        let output: Vec<u8> = (0..254)
+            .map(|_| {
+                shift(&mut noise);
+                noise.output()
+            })
+            .collect();
+        assert_eq!(output[..127], output[127..]);
+        //127 is prime, so a sequence that is not constant has no shorter period
+        assert!(output.contains(&0) && output.contains(&15));
+    }
+
+    #[test]
+    fn output_is_the_inverted_lfsr_bit_0() {
+        let mut noise = playing(0x00);
+        noise.lfsr = 0x7FFE;
+        assert_eq!(noise.output(), 15);
+        noise.lfsr = 0x7FFF;
+        assert_eq!(noise.output(), 0);
+    }
+
+    #[test]
+    fn clock_shifts_14_and_15_stop_the_lfsr() {
+        let mut noise = playing(0xE0);
+        for _ in 0..DIVISORS[0] << 14 {
+            noise.do_cycle();
+        }
+        assert_eq!(noise.lfsr, 0x7FFF);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length::LengthCounter;
+use crate::apu::ChannelState;
+
+//Square channels 1 and 2, only channel 1 has the frequency sweep
+pub struct Pulse {
+    enabled: bool,
+
+    duty: u8,
+    duty_pos: u8,
+    frequency: u16,
+    timer: u16,
+
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+
+    enabled: bool,
+    timer: u8,
+    shadow: u16,
+    //Negate mode used since the last trigger
+    negated: bool,
+}
+
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const DUTY_RATIOS: [f32; 4] = [0.125, 0.25, 0.5, 0.75];
+
+const FREQUENCY_MAX: u16 = 2047;
+const LENGTH_MAX: u16 = 64;
+
+impl Pulse {
+    pub fn new(with_sweep: bool) -> Self {
+        Pulse {
+            enabled: false,
+
+            duty: 0,
+            duty_pos: 0,
+            frequency: 0,
+            timer: 0,
+
+            length: LengthCounter::new(LENGTH_MAX),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    pub fn do_cycle(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+
+    //Digital output, 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let high = DUTY_PATTERNS[self.duty as usize] & (0x80 >> self.duty_pos) > 0;
+        if high {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            frequency: 131072.0 / (2048 - self.frequency) as f64,
+            volume: self.envelope.volume(),
+            duty: Some(DUTY_RATIOS[self.duty as usize]),
+            audible: true,
+        }
+    }
+
+    //Registers NRx0 to NRx4, unused bits read as 1
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => match self.sweep.as_ref() {
+                Some(sweep) => 0x80 | sweep.read(),
+                None => 0xFF,
+            },
+            1 => 0x3F | self.duty << 6,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            _ => 0xBF | (self.length.is_enabled() as u8) << 6,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    if sweep.write(data) {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.length.load(data & 0x3F);
+            }
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | (data as u16 & 0x07) << 8;
+                self.length.set_enabled(data & 0b01000000 > 0);
+                if data & 0b10000000 > 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    //Only the length part of NRx1 is writable while the APU is off
+    pub fn write_length(&mut self, data: u8) {
+        self.length.load(data & 0x3F);
+    }
+
+    //Clears every register, the length counter survives on DMG
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(LENGTH_MAX));
+        *self = Pulse::new(self.sweep.is_some());
+        self.length = length;
+        self.length.set_enabled(false);
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            if sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let result = match self.sweep.as_mut() {
+            Some(sweep) => sweep.clock(),
+            None => return,
+        };
+
+        match result {
+            SweepResult::Overflow => self.enabled = false,
+            SweepResult::Frequency(frequency) => self.frequency = frequency,
+            SweepResult::Unchanged => {}
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+}
+
+enum SweepResult {
+    Unchanged,
+    Frequency(u16),
+    Overflow,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+
+            enabled: false,
+            timer: 0,
+            shadow: 0,
+            negated: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        self.period << 4 | (self.negate as u8) << 3 | self.shift
+    }
+
+    //Returns true when leaving negate mode after a negated calculation, which disables the channel
+    fn write(&mut self, data: u8) -> bool {
+        let was_negate = self.negate;
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0b00001000 > 0;
+        self.shift = data & 0b00000111;
+
+        was_negate && !self.negate && self.negated
+    }
+
+    //Returns true when the initial overflow check disables the channel
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow = frequency;
+        self.timer = self.reload_value();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negated = false;
+
+        self.shift != 0 && self.calculate() > FREQUENCY_MAX
+    }
+
+    fn clock(&mut self) -> SweepResult {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return SweepResult::Unchanged;
+        }
+
+        self.timer = self.reload_value();
+        if !self.enabled || self.period == 0 {
+            return SweepResult::Unchanged;
+        }
+
+        let frequency = self.calculate();
+        if frequency > FREQUENCY_MAX {
+            return SweepResult::Overflow;
+        }
+        if self.shift == 0 {
+            return SweepResult::Unchanged;
+        }
+
+        self.shadow = frequency;
+        //The new frequency is checked again right away
+        if self.calculate() > FREQUENCY_MAX {
+            return SweepResult::Overflow;
+        }
+
+        SweepResult::Frequency(frequency)
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    //A period of 0 is treated as 8
+    fn reload_value(&self) -> u8 {
+        if self.period == 0 {
+            8
+        } else {
+            self.period
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Full volume channel triggered at a frequency, NR10 only matters with the sweep
+    fn triggered(with_sweep: bool, nr10: u8, frequency: u16) -> Pulse {
+        let mut pulse = Pulse::new(with_sweep);
+        pulse.write(0, nr10);
+        pulse.write(1, 0x80);
+        pulse.write(2, 0xF0);
+        pulse.write(3, frequency as u8);
+        pulse.write(4, 0x80 | (frequency >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn duty_patterns() {
+        let expected = [
+            [0, 0, 0, 0, 0, 0, 0, 15],
+            [15, 0, 0, 0, 0, 0, 0, 15],
+            [15, 0, 0, 0, 0, 15, 15, 15],
+            [0, 15, 15, 15, 15, 15, 15, 0],
+        ];
+        for (duty, expected) in expected.iter().enumerate() {
+            let mut pulse = triggered(false, 0, FREQUENCY_MAX);
+            pulse.write(1, (duty as u8) << 6);
+
+            //Each step lasts (2048 - 2047) * 4 cycles
+            let output: Vec<u8> = (0..8)
+                .map(|_| {
+                    let output = pulse.output();
+                    (0..4).for_each(|_| pulse.do_cycle());
+                    output
+                })
+                .collect();
+            assert_eq!(&output[..], &expected[..], "duty {}", duty);
+        }
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_channel_1() {
+        //1500 + 750 does not fit in 11 bits
+        assert!(!triggered(true, 0x01, 1500).is_enabled());
+        //Channel 2 has no sweep
+        assert!(triggered(false, 0x01, 1500).is_enabled());
+    }
+
+    #[test]
+    fn sweep_raises_the_frequency_until_it_overflows() {
+        let mut pulse = triggered(true, 0x11, 600);
+        assert!(pulse.is_enabled());
+
+        let mut frequencies = Vec::new();
+        while pulse.is_enabled() {
+            pulse.clock_sweep();
+            frequencies.push(pulse.frequency);
+        }
+        //2025 is only checked against the next step, 2025 + 1012 does not fit in 11 bits
+        assert_eq!(frequencies, vec![900, 1350, 1350]);
+    }
+
+    #[test]
+    fn leaving_negate_mode_after_a_negated_step_disables_channel_1() {
+        let mut pulse = triggered(true, 0x19, 1024);
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 512);
+        assert!(pulse.is_enabled());
+
+        pulse.write(0, 0x11);
+        assert!(!pulse.is_enabled());
+    }
+
+    #[test]
+    fn length_expiry_disables_the_channel() {
+        let mut pulse = triggered(false, 0, 0);
+        pulse.write(1, 0x3E);
+        pulse.write(4, 0xC0);
+        pulse.clock_length();
+        assert!(pulse.is_enabled());
+        pulse.clock_length();
+        assert!(!pulse.is_enabled());
+    }
+}
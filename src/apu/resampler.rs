@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+//Windowed sinc resampler, band-limits the mixer output to the host sample rate
+pub struct Resampler {
+    ratio: f64,
+    next: f64,
+    half_taps: usize,
+    kernels: Vec<f32>,
+    input: VecDeque<[f32; 2]>,
+}
+
+const PHASES: usize = 128;
+const ZERO_CROSSINGS: f64 = 8.0;
+//Cutoff relative to the output rate, leaves room for the transition band
+const CUTOFF: f64 = 0.45;
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        let cutoff = (CUTOFF * output_rate / input_rate).min(0.5);
+        let half_taps = (ZERO_CROSSINGS / (2.0 * cutoff)).ceil() as usize;
+        let taps = half_taps * 2;
+
+        let mut kernels = vec![0.0; PHASES * taps];
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let kernel = &mut kernels[phase * taps..(phase + 1) * taps];
+
+            let mut sum = 0.0;
+            for (j, tap) in kernel.iter_mut().enumerate() {
+                let t = j as f64 - (half_taps - 1) as f64 - frac;
+                let value = sinc(2.0 * cutoff * t) * blackman(t / half_taps as f64);
+                *tap = value as f32;
+                sum += value;
+            }
+
+            //Unity gain at DC for every phase
+            for tap in kernel.iter_mut() {
+                *tap /= sum as f32;
+            }
+        }
+
+        Resampler {
+            ratio: input_rate / output_rate,
+            next: half_taps as f64,
+            half_taps,
+            kernels,
+            input: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: [f32; 2], output: &mut Vec<[f32; 2]>) {
+        self.input.push_back(sample);
+
+        let taps = self.half_taps * 2;
+        while self.next + ((self.half_taps + 1) as f64) < self.input.len() as f64 {
+            let mut base = self.next.floor() as usize;
+            let mut phase = ((self.next - base as f64) * PHASES as f64).round() as usize;
+            if phase == PHASES {
+                base += 1;
+                phase = 0;
+            }
+
+            let kernel = &self.kernels[phase * taps..(phase + 1) * taps];
+            let start = base + 1 - self.half_taps;
+            let mut out = [0.0; 2];
+            for (j, tap) in kernel.iter().enumerate() {
+                let sample = self.input[start + j];
+                out[0] += sample[0] * tap;
+                out[1] += sample[1] * tap;
+            }
+            output.push(out);
+
+            self.next += self.ratio;
+        }
+
+        while self.next > (self.half_taps + 1) as f64 {
+            self.input.pop_front();
+            self.next -= 1.0;
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+//Window over -1..1
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+
+    let x = (x + 1.0) / 2.0;
+    0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT_RATE: f64 = 131072.0;
+    const OUTPUT_RATE: f64 = 48000.0;
+
+    #[test]
+    fn output_follows_the_output_rate() {
+        let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE);
+        let mut output = Vec::new();
+        for _ in 0..INPUT_RATE as usize {
+            resampler.push([0.0; 2], &mut output);
+        }
+
+        //Short of the filter delay
+        let missing = OUTPUT_RATE as usize - output.len();
+        assert!(
+            missing <= resampler.half_taps,
+            "{} samples missing",
+            missing
+        );
+    }
+
+    #[test]
+    fn keeps_dc_at_unity_gain() {
+        let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE);
+        let mut output = Vec::new();
+        for _ in 0..4096 {
+            resampler.push([0.5, -0.25], &mut output);
+        }
+
+        for sample in output.iter() {
+            assert!((sample[0] - 0.5).abs() < 1e-3, "{:?}", sample);
+            assert!((sample[1] + 0.25).abs() < 1e-3, "{:?}", sample);
+        }
+    }
+}
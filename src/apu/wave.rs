@@ -0,0 +1,252 @@
+use crate::apu::length::LengthCounter;
+use crate::apu::ChannelState;
+
+//Channel 3, plays the 32 4-bit samples of the wave RAM
+pub struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+
+    position: u8,
+    sample: u8,
+    //Set on the cycle the channel fetches from wave RAM, the only time the CPU can reach it
+    just_read: bool,
+
+    length: LengthCounter,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+const WAVE_RAM_SIZE: usize = 0x10;
+const LENGTH_MAX: u16 = 256;
+
+//Volume codes 0 to 3 mute, play at 100%, 50% and 25%
+const VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+impl Wave {
+    pub fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+
+            position: 0,
+            sample: 0,
+            just_read: false,
+
+            length: LengthCounter::new(LENGTH_MAX),
+            ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    pub fn do_cycle(&mut self) {
+        self.just_read = false;
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.enabled {
+                self.position = (self.position + 1) % 32;
+                self.sample = self.ram[self.position as usize / 2];
+                self.just_read = true;
+            }
+        }
+    }
+
+    //Digital output, 0 to 15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let sample = if self.position % 2 == 0 {
+            self.sample >> 4
+        } else {
+            self.sample & 0x0F
+        };
+        sample >> VOLUME_SHIFTS[self.volume_code as usize]
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            frequency: 65536.0 / (2048 - self.frequency) as f64,
+            volume: 0x0F >> VOLUME_SHIFTS[self.volume_code as usize],
+            duty: None,
+            audible: true,
+        }
+    }
+
+    //Registers NR30 to NR34, unused bits read as 1
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0x7F | (self.dac_enabled as u8) << 7,
+            1 => 0xFF,
+            2 => 0x9F | self.volume_code << 5,
+            3 => 0xFF,
+            _ => 0xBF | (self.length.is_enabled() as u8) << 6,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = data & 0b10000000 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(data),
+            2 => self.volume_code = (data >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | (data as u16 & 0x07) << 8;
+                self.length.set_enabled(data & 0b01000000 > 0);
+                if data & 0b10000000 > 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    //While playing, the DMG only lets the CPU reach the byte being read, on the cycle it is read
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if !self.enabled {
+            return self.ram[addr as usize];
+        }
+
+        if self.just_read {
+            self.ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        if !self.enabled {
+            self.ram[addr as usize] = data;
+        } else if self.just_read {
+            self.ram[self.position as usize / 2] = data;
+        }
+    }
+
+    pub fn write_length(&mut self, data: u8) {
+        self.length.load(data);
+    }
+
+    //Wave RAM is kept along with the length counter
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(LENGTH_MAX));
+        let ram = self.ram;
+        *self = Wave::new();
+        self.length = length;
+        self.length.set_enabled(false);
+        self.ram = ram;
+    }
+
+    fn trigger(&mut self) {
+        //DMG retriggering while a sample is fetched corrupts the start of wave RAM
+        if self.enabled && self.timer == 1 {
+            let index = ((self.position as usize + 1) % 32) / 2;
+            if index < 4 {
+                self.ram[0] = self.ram[index];
+            } else {
+                let start = index & !0x03;
+                for i in 0..4 {
+                    self.ram[i] = self.ram[start + i];
+                }
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Frequency 2047, a sample every 2 cycles
+    fn playing(ram: [u8; WAVE_RAM_SIZE], nr32: u8) -> Wave {
+        let mut wave = Wave::new();
+        for (addr, &data) in ram.iter().enumerate() {
+            wave.write_ram(addr as u16, data);
+        }
+        wave.write(0, 0x80);
+        wave.write(2, nr32);
+        wave.write(3, 0xFF);
+        wave.write(4, 0x87);
+        wave
+    }
+
+    fn samples(wave: &mut Wave, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                wave.do_cycle();
+                wave.do_cycle();
+                wave.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plays_high_nibbles_first_from_sample_1() {
+        let mut ram = [0; WAVE_RAM_SIZE];
+        for (i, byte) in ram.iter_mut().enumerate() {
+            let sample = (i as u8 * 2) % 16;
+            *byte = sample << 4 | (sample + 1);
+        }
+        let mut wave = playing(ram, 0x20);
+
+        let expected: Vec<u8> = (1..=32).map(|i| i % 16).collect();
+        assert_eq!(samples(&mut wave, 32), expected);
+    }
+
+    #[test]
+    fn volume_code_shifts_the_samples() {
+        for (nr32, expected) in [(0x00, 0), (0x20, 15), (0x40, 7), (0x60, 3)].iter() {
+            let mut wave = playing([0xFF; WAVE_RAM_SIZE], *nr32);
+            assert_eq!(samples(&mut wave, 4), vec![*expected; 4]);
+        }
+    }
+
+    #[test]
+    fn wave_ram_is_only_reachable_on_fetches_while_playing() {
+        let mut wave = playing([0x12; WAVE_RAM_SIZE], 0x20);
+        assert_eq!(wave.read_ram(0), 0xFF);
+        wave.do_cycle();
+        wave.do_cycle();
+        assert_eq!(wave.read_ram(5), 0x12);
+
+        wave.write(0, 0x00);
+        assert_eq!(wave.read_ram(0), 0x12);
+    }
+}
@@ -0,0 +1,20 @@
+//Receives the stereo samples produced by the APU
+pub trait AudioSink {
+    //Rate the samples are wanted at, None when nobody listens and the APU can skip mixing
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    fn sample_rate(&self) -> Option<u32>;
+
+    //Interleaved left/right samples between -1.0 and 1.0
+    fn write(&mut self, samples: &[f32]);
+}
+
+//Headless runs, no samples are produced
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    fn write(&mut self, _samples: &[f32]) {}
+}
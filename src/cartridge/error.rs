@@ -0,0 +1,67 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::cartridge::patch::PatchError;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    TruncatedRom { size: usize, expected: usize },
+    UnsupportedMapper(u8),
+    BadHeaderChecksum { expected: u8, actual: u8 },
+    RamSizeMismatch { cartridge_type: u8, ram_size: u8 },
+    Patch(PatchError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "unable to read ROM: {}", e),
+            CartridgeError::TruncatedRom { size, expected } => write!(
+                f,
+                "ROM is truncated: {} bytes, expected at least {}",
+                size, expected
+            ),
+            CartridgeError::UnsupportedMapper(code) => {
+                write!(f, "unsupported cartridge type 0x{:02X}", code)
+            }
+            CartridgeError::BadHeaderChecksum { expected, actual } => write!(
+                f,
+                "bad header checksum: 0x{:02X} in header, computed 0x{:02X}",
+                expected, actual
+            ),
+            CartridgeError::RamSizeMismatch {
+                cartridge_type,
+                ram_size,
+            } => write!(
+                f,
+                "RAM size code 0x{:02X} does not match cartridge type 0x{:02X}",
+                ram_size, cartridge_type
+            ),
+            CartridgeError::Patch(e) => write!(f, "unable to apply patch: {}", e),
+        }
+    }
+}
+
+impl error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            CartridgeError::Patch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+impl From<PatchError> for CartridgeError {
+    fn from(e: PatchError) -> Self {
+        CartridgeError::Patch(e)
+    }
+}
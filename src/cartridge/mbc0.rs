@@ -12,18 +12,15 @@ impl Mbc0 {
 
 impl Cartridge for Mbc0 {
     fn read_rom(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        self.rom.get(addr as usize).cloned().unwrap_or(0xFF)
     }
 
     fn read_ram(&self, _addr: u16) -> u8 {
-        panic!("No RAM on MBC0 cartridge !");
+        //No RAM on MBC0 cartridge, the bus floats
+        0xFF
     }
 
-    fn write_rom(&mut self, addr: u16, data: u8) {
-        self.rom[addr as usize] = data;
-    }
+    fn write_rom(&mut self, _addr: u16, _data: u8) {}
 
-    fn write_ram(&self, _addr: u16, _data: u8) {
-        panic!("No RAM on MBC0 cartridge !");
-    }
+    fn write_ram(&mut self, _addr: u16, _data: u8) {}
 }
@@ -0,0 +1,209 @@
+use crate::cartridge::rtc::Rtc;
+use crate::cartridge::Cartridge;
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    rom_bank: u8,
+    //0x00-0x03 select a RAM bank, 0x08-0x0C a clock register
+    ram_bank: u8,
+    ram_enabled: bool,
+    //Writing 0 then 1 latches the clock
+    latch_prepared: bool,
+
+    battery: bool,
+    rtc: Option<Rtc>,
+}
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+const RAM_ENABLE_VALUE: u8 = 0x0A;
+const RTC_FIRST_REG: u8 = 0x08;
+const RTC_LAST_REG: u8 = 0x0C;
+
+impl Mbc3 {
+    pub fn new(data: Vec<u8>, ram_size: usize, timer: bool, battery: bool) -> Self {
+        Mbc3 {
+            rom: data,
+            ram: vec![0; ram_size],
+
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            latch_prepared: false,
+
+            battery,
+            rtc: if timer { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() || self.ram_bank > 0x03 {
+            return None;
+        }
+
+        Some((self.ram_bank as usize * RAM_BANK_SIZE + addr as usize) % self.ram.len())
+    }
+
+    fn rtc_reg(&self) -> Option<usize> {
+        match self.ram_bank {
+            RTC_FIRST_REG...RTC_LAST_REG => Some((self.ram_bank - RTC_FIRST_REG) as usize),
+            _ => None,
+        }
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let index = match addr {
+            0x0000...0x3FFF => addr as usize,
+            _ => {
+                let bank = self.rom_bank as usize % self.rom_bank_count();
+                bank * ROM_BANK_SIZE + (addr as usize - ROM_BANK_SIZE)
+            }
+        };
+
+        self.rom.get(index).cloned().unwrap_or(0xFF)
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match (self.rtc.as_ref(), self.rtc_reg(), self.ram_index(addr)) {
+            (Some(rtc), Some(reg), _) => rtc.read(reg),
+            (_, _, Some(index)) => self.ram[index],
+            _ => 0xFF,
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = data & 0x0F == RAM_ENABLE_VALUE,
+            //7 bits ROM bank, bank 0 maps bank 1
+            0x2000...0x3FFF => self.rom_bank = (data & 0x7F).max(1),
+            0x4000...0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000...0x7FFF => {
+                if self.latch_prepared && data == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
+                }
+                self.latch_prepared = data == 0x00;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        if let Some(reg) = self.rtc_reg() {
+            if let Some(rtc) = self.rtc.as_mut() {
+                rtc.write(reg, data);
+            }
+        } else if let Some(index) = self.ram_index(addr) {
+            self.ram[index] = data;
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        (self.rom_bank as usize % self.rom_bank_count()) as u16
+    }
+
+    fn do_cycle(&mut self) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.do_cycle();
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge() -> Mbc3 {
+        let mut mbc3 = Mbc3::new(vec![0; 4 * ROM_BANK_SIZE], 4 * RAM_BANK_SIZE, true, true);
+        mbc3.write_rom(0x0000, RAM_ENABLE_VALUE);
+        mbc3
+    }
+
+    #[test]
+    fn clock_registers_are_read_once_latched() {
+        let mut mbc3 = cartridge();
+        mbc3.write_rom(0x4000, 0x09);
+        mbc3.write_ram(0x0000, 42);
+        assert_eq!(mbc3.read_ram(0x0000), 0);
+
+        mbc3.write_rom(0x6000, 0x00);
+        mbc3.write_rom(0x6000, 0x01);
+        assert_eq!(mbc3.read_ram(0x0000), 42);
+    }
+
+    #[test]
+    fn latch_needs_0_then_1() {
+        let mut mbc3 = cartridge();
+        mbc3.write_rom(0x4000, 0x08);
+        mbc3.write_ram(0x0000, 30);
+        mbc3.write_rom(0x6000, 0x01);
+        assert_eq!(mbc3.read_ram(0x0000), 0);
+    }
+
+    #[test]
+    fn clock_and_ram_banks_are_separate() {
+        let mut mbc3 = cartridge();
+        mbc3.write_rom(0x4000, 0x02);
+        mbc3.write_ram(0x0010, 0x55);
+        mbc3.write_rom(0x4000, 0x08);
+        mbc3.write_ram(0x0010, 0x12);
+
+        mbc3.write_rom(0x4000, 0x02);
+        assert_eq!(mbc3.read_ram(0x0010), 0x55);
+        assert_eq!(mbc3.ram()[2 * RAM_BANK_SIZE + 0x10], 0x55);
+    }
+
+    #[test]
+    fn clock_saved_after_the_ram() {
+        let mut mbc3 = cartridge();
+        mbc3.write_rom(0x4000, 0x0A);
+        mbc3.write_ram(0x0000, 7);
+
+        let trailer = mbc3.rtc().map(Rtc::to_trailer).unwrap();
+        let mut loaded = cartridge();
+        assert!(loaded.rtc_mut().unwrap().load_trailer(&trailer));
+        loaded.write_rom(0x6000, 0x00);
+        loaded.write_rom(0x6000, 0x01);
+        loaded.write_rom(0x4000, 0x0A);
+        assert_eq!(loaded.read_ram(0x0000), 7);
+    }
+}
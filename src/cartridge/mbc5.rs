@@ -0,0 +1,229 @@
+use crate::cartridge::{Cartridge, RumbleCallback};
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    rom_bank: u16,
+    ram_bank: u8,
+    ram_enabled: bool,
+
+    battery: bool,
+    rumble: bool,
+    rumble_on: bool,
+    rumble_callback: Option<RumbleCallback>,
+}
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+const RAM_ENABLE_VALUE: u8 = 0x0A;
+const RUMBLE_MOTOR_MASK: u8 = 0b00001000;
+
+impl Mbc5 {
+    pub fn new(data: Vec<u8>, ram_size: usize, rumble: bool, battery: bool) -> Self {
+        Mbc5 {
+            rom: data,
+            ram: vec![0; ram_size],
+
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+
+            battery,
+            rumble,
+            rumble_on: false,
+            rumble_callback: None,
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        (self.ram.len() / RAM_BANK_SIZE).max(1)
+    }
+
+    fn ram_index(&self, addr: u16) -> usize {
+        let bank = self.ram_bank as usize % self.ram_bank_count();
+        (bank * RAM_BANK_SIZE + addr as usize) % self.ram.len()
+    }
+
+    fn set_rumble(&mut self, on: bool) {
+        if self.rumble_on == on {
+            return;
+        }
+
+        self.rumble_on = on;
+        if let Some(callback) = self.rumble_callback.as_mut() {
+            callback(on);
+        }
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let index = match addr {
+            0x0000...0x3FFF => addr as usize,
+            _ => {
+                let bank = self.rom_bank as usize % self.rom_bank_count();
+                bank * ROM_BANK_SIZE + (addr as usize - ROM_BANK_SIZE)
+            }
+        };
+
+        self.rom.get(index).cloned().unwrap_or(0xFF)
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        self.ram[self.ram_index(addr)]
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = data & 0x0F == RAM_ENABLE_VALUE,
+            //Lower 8 bits of the ROM bank, bank 0 can be mapped
+            0x2000...0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            //9th bit of the ROM bank
+            0x3000...0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 1) << 8),
+            0x4000...0x5FFF => {
+                if self.rumble {
+                    //Bit 3 drives the motor on rumble cartridges
+                    self.ram_bank = data & 0x07;
+                    self.set_rumble(data & RUMBLE_MOTOR_MASK > 0);
+                } else {
+                    self.ram_bank = data & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let index = self.ram_index(addr);
+        self.ram[index] = data;
+    }
+
+    fn rom_bank(&self) -> u16 {
+        (self.rom_bank as usize % self.rom_bank_count()) as u16
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const ROM_BANKS: usize = 512;
+    const RAM_BANKS: usize = 4;
+
+    //Each ROM bank starts with its number
+    fn cartridge(rumble: bool) -> Mbc5 {
+        let mut rom = vec![0; ROM_BANKS * ROM_BANK_SIZE];
+        for bank in 0..ROM_BANKS {
+            rom[bank * ROM_BANK_SIZE..bank * ROM_BANK_SIZE + 2]
+                .copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+
+        let mut mbc5 = Mbc5::new(rom, RAM_BANKS * RAM_BANK_SIZE, rumble, true);
+        mbc5.write_rom(0x0000, RAM_ENABLE_VALUE);
+        mbc5
+    }
+
+    fn mapped_bank(mbc5: &Mbc5) -> u16 {
+        u16::from_le_bytes([mbc5.read_rom(0x4000), mbc5.read_rom(0x4001)])
+    }
+
+    #[test]
+    fn rom_bank_has_9_bits() {
+        let mut mbc5 = cartridge(false);
+        assert_eq!(mapped_bank(&mbc5), 1);
+
+        mbc5.write_rom(0x2000, 0x2A);
+        mbc5.write_rom(0x3000, 0x01);
+        assert_eq!(mapped_bank(&mbc5), 0x12A);
+        assert_eq!(mbc5.rom_bank(), 0x12A);
+
+        mbc5.write_rom(0x2FFF, 0xFF);
+        assert_eq!(mapped_bank(&mbc5), 0x1FF);
+        mbc5.write_rom(0x3FFF, 0x00);
+        assert_eq!(mapped_bank(&mbc5), 0x0FF);
+    }
+
+    #[test]
+    fn bank_0_can_be_mapped_at_0x4000() {
+        let mut mbc5 = cartridge(false);
+        mbc5.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc5), 0);
+        assert_eq!(mbc5.read_rom(0x0000), mbc5.read_rom(0x4000));
+    }
+
+    #[test]
+    fn ram_banks_wrap_around_the_ram_size() {
+        let mut mbc5 = cartridge(false);
+        mbc5.write_rom(0x4000, 0x01);
+        mbc5.write_ram(0x0010, 0x55);
+        assert_eq!(mbc5.ram()[RAM_BANK_SIZE + 0x10], 0x55);
+
+        mbc5.write_rom(0x4000, 0x01 + RAM_BANKS as u8);
+        assert_eq!(mbc5.read_ram(0x0010), 0x55);
+
+        mbc5.write_rom(0x0000, 0x00);
+        assert_eq!(mbc5.read_ram(0x0010), 0xFF);
+    }
+
+    #[test]
+    fn rumble_bit_drives_the_motor_instead_of_the_ram_bank() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let recorded = calls.clone();
+
+        let mut mbc5 = cartridge(true);
+        mbc5.set_rumble_callback(Box::new(move |on| recorded.borrow_mut().push(on)));
+        mbc5.write_rom(0x4000, 0x02);
+        mbc5.write_ram(0x0000, 0x77);
+
+        //Bank 2 stays selected while the motor turns on, only changes are reported
+        mbc5.write_rom(0x4000, RUMBLE_MOTOR_MASK | 0x02);
+        mbc5.write_rom(0x4000, RUMBLE_MOTOR_MASK | 0x02);
+        assert_eq!(mbc5.read_ram(0x0000), 0x77);
+        mbc5.write_rom(0x4000, 0x02);
+        assert_eq!(*calls.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn no_motor_without_rumble() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let recorded = calls.clone();
+
+        let mut mbc5 = cartridge(false);
+        mbc5.set_rumble_callback(Box::new(move |on| recorded.borrow_mut().push(on)));
+        mbc5.write_rom(0x4000, RUMBLE_MOTOR_MASK);
+        assert!(calls.borrow().is_empty());
+    }
+}
@@ -1,33 +1,203 @@
-use std::fs;
-use std::path;
-
+mod error;
 mod mbc0;
+mod mbc3;
+mod mbc5;
+pub mod patch;
+pub mod rtc;
+pub mod save;
+
+pub use self::error::CartridgeError;
 
 enum CartridgeTypes {
     MBC0 = 0x00,
+    MBC3 = 0x11,
+    MBC5 = 0x19,
 }
 
+const TITLE_START: usize = 0x134;
 const CARTRIDGE_TYPE_BYTE: usize = 0x147;
+const ROM_SIZE_BYTE: usize = 0x148;
+const RAM_SIZE_BYTE: usize = 0x149;
+const HEADER_CHECKSUM_BYTE: usize = 0x14D;
+const HEADER_END: usize = 0x150;
+
+const MIN_ROM_SIZE: usize = 0x8000;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+pub type RumbleCallback = Box<dyn FnMut(bool)>;
 
 pub trait Cartridge {
     fn read_rom(&self, addr: u16) -> u8;
     fn read_ram(&self, addr: u16) -> u8;
 
     fn write_rom(&mut self, addr: u16, data: u8);
-    fn write_ram(&self, addr: u16, data: u8);
+    fn write_ram(&mut self, addr: u16, data: u8);
+
+    //Bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    //Called every cycle at normal speed, for the clock of MBC3 cartridges
+    fn do_cycle(&mut self) {}
+
+    //Called with the new motor state each time it changes on rumble cartridges
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {}
+
+    //Battery backed RAM, persisted in the .sav file
+    fn has_battery(&self) -> bool {
+        false
+    }
+
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn load_ram(&mut self, _data: &[u8]) {}
+
+    //Clock of MBC3 and HuC3 cartridges, saved after the RAM
+    fn rtc(&self) -> Option<&rtc::Rtc> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut rtc::Rtc> {
+        None
+    }
 }
 
-pub fn new(path: &path::Path) -> Box<Cartridge> {
-    let rom = fs::read(path).unwrap();
+pub fn new(rom: &[u8]) -> Result<Box<dyn Cartridge>, CartridgeError> {
+    check_header(rom)?;
+    let rom = rom.to_vec();
 
-    match get_cartridge_type(rom[CARTRIDGE_TYPE_BYTE]) {
+    let type_byte = rom[CARTRIDGE_TYPE_BYTE];
+    let cartridge_type = get_cartridge_type(type_byte)?;
+    let ram_size = get_ram_size(type_byte, rom[RAM_SIZE_BYTE])?;
+
+    Ok(match cartridge_type {
         CartridgeTypes::MBC0 => Box::new(mbc0::Mbc0::new(rom)),
+        CartridgeTypes::MBC3 => Box::new(mbc3::Mbc3::new(
+            rom,
+            ram_size,
+            has_timer(type_byte),
+            has_battery(type_byte),
+        )),
+        CartridgeTypes::MBC5 => Box::new(mbc5::Mbc5::new(
+            rom,
+            ram_size,
+            has_rumble(type_byte),
+            has_battery(type_byte),
+        )),
+    })
+}
+
+fn check_header(rom: &[u8]) -> Result<(), CartridgeError> {
+    if rom.len() < HEADER_END {
+        return Err(CartridgeError::TruncatedRom {
+            size: rom.len(),
+            expected: HEADER_END,
+        });
+    }
+
+    let rom_size = get_rom_size(rom[ROM_SIZE_BYTE]);
+    if rom.len() < rom_size {
+        return Err(CartridgeError::TruncatedRom {
+            size: rom.len(),
+            expected: rom_size,
+        });
+    }
+
+    let checksum = rom[TITLE_START..HEADER_CHECKSUM_BYTE]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+    if checksum != rom[HEADER_CHECKSUM_BYTE] {
+        return Err(CartridgeError::BadHeaderChecksum {
+            expected: rom[HEADER_CHECKSUM_BYTE],
+            actual: checksum,
+        });
+    }
+
+    Ok(())
+}
+
+//Codes 0x52 to 0x54 are 72, 80 and 96 banks of 16 KiB, unknown codes are taken as the largest size
+fn get_rom_size(size_byte: u8) -> usize {
+    match size_byte {
+        0x52 => 72 * ROM_BANK_SIZE,
+        0x53 => 80 * ROM_BANK_SIZE,
+        0x54 => 96 * ROM_BANK_SIZE,
+        _ => MIN_ROM_SIZE << size_byte.min(8),
+    }
+}
+
+fn get_cartridge_type(type_byte: u8) -> Result<CartridgeTypes, CartridgeError> {
+    match type_byte {
+        0x00 => Ok(CartridgeTypes::MBC0),
+        0x0F...0x13 => Ok(CartridgeTypes::MBC3),
+        0x19...0x1E => Ok(CartridgeTypes::MBC5),
+        _ => Err(CartridgeError::UnsupportedMapper(type_byte)),
+    }
+}
+
+fn get_ram_size(type_byte: u8, size_byte: u8) -> Result<usize, CartridgeError> {
+    let size = match size_byte {
+        0x00 => Some(0),
+        0x01 => Some(0x800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x8000),
+        0x04 => Some(0x20000),
+        0x05 => Some(0x10000),
+        _ => None,
+    };
+
+    match size {
+        Some(size) if (size > 0) == has_ram(type_byte) => Ok(size),
+        _ => Err(CartridgeError::RamSizeMismatch {
+            cartridge_type: type_byte,
+            ram_size: size_byte,
+        }),
+    }
+}
+
+fn has_ram(type_byte: u8) -> bool {
+    match type_byte {
+        0x02 | 0x03 | 0x08 | 0x09 | 0x0C | 0x0D | 0x10 | 0x12 | 0x13 | 0x1A | 0x1B | 0x1D
+        | 0x1E | 0xFF => true,
+        _ => false,
     }
 }
 
-fn get_cartridge_type(type_byte: u8) -> CartridgeTypes {
+fn has_timer(type_byte: u8) -> bool {
     match type_byte {
-        0x00 => CartridgeTypes::MBC0,
-        _ => panic!("Not a Gameboy rom file !"),
+        0x0F | 0x10 => true,
+        _ => false,
+    }
+}
+
+fn has_rumble(type_byte: u8) -> bool {
+    match type_byte {
+        0x1C...0x1E => true,
+        _ => false,
+    }
+}
+
+fn has_battery(type_byte: u8) -> bool {
+    match type_byte {
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_size_codes() {
+        assert_eq!(get_rom_size(0x00), 0x8000);
+        assert_eq!(get_rom_size(0x05), 0x100000);
+        assert_eq!(get_rom_size(0x08), 0x800000);
+        assert_eq!(get_rom_size(0x52), 0x120000);
+        assert_eq!(get_rom_size(0x53), 0x140000);
+        assert_eq!(get_rom_size(0x54), 0x180000);
     }
 }
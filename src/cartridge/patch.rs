@@ -0,0 +1,488 @@
+extern crate crc32fast;
+
+use std::error;
+use std::fmt;
+
+use crc32fast::Hasher;
+
+//IPS, UPS and BPS soft patches, applied in memory before building the cartridge
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownFormat,
+    Corrupt,
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    ChecksumMismatch {
+        what: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+//Source, target and patch CRC32
+const FOOTER_SIZE: usize = 12;
+
+//Largest cartridge, bigger targets come from a corrupt or crafted patch
+const MAX_TARGET_SIZE: usize = 0x800000;
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+    let mut target = rom.to_vec();
+
+    loop {
+        if reader.remaining().starts_with(IPS_EOF) {
+            reader.skip(IPS_EOF.len())?;
+            break;
+        }
+
+        let offset = reader.read_be(3)?;
+        let size = reader.read_be(2)?;
+        let data = if size == 0 {
+            //RLE record
+            let count = reader.read_be(2)?;
+            vec![reader.read_u8()?; count]
+        } else {
+            reader.read_bytes(size)?.to_vec()
+        };
+
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    //Optional truncation extension
+    if reader.remaining().len() == 3 {
+        let size = reader.read_be(3)?;
+        target.truncate(size);
+    }
+
+    Ok(target)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - FOOTER_SIZE], UPS_MAGIC.len());
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SizeMismatch {
+            expected: source_size,
+            actual: rom.len(),
+        });
+    }
+
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::Corrupt);
+    }
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+
+    let mut offset: usize = 0;
+    while !reader.remaining().is_empty() {
+        offset = checked_add(offset, reader.read_varint()?)?;
+        loop {
+            let xor = reader.read_u8()?;
+            if offset < target.len() {
+                target[offset] ^= xor;
+            }
+            offset = checked_add(offset, 1)?;
+
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(&target, footer)?;
+    Ok(target)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - FOOTER_SIZE], BPS_MAGIC.len());
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.skip(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SizeMismatch {
+            expected: source_size,
+            actual: rom.len(),
+        });
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::Corrupt);
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while !reader.remaining().is_empty() {
+        let action = reader.read_varint()?;
+        let length = (action >> 2) + 1;
+        if checked_add(target.len(), length)? > target_size {
+            return Err(PatchError::Corrupt);
+        }
+
+        match action & 0b11 {
+            //SourceRead
+            0 => {
+                let start = target.len();
+                let data = rom
+                    .get(start..checked_add(start, length)?)
+                    .ok_or(PatchError::Corrupt)?;
+                target.extend_from_slice(data);
+            }
+            //TargetRead
+            1 => target.extend_from_slice(reader.read_bytes(length)?),
+            //SourceCopy
+            2 => {
+                source_offset = relative_offset(source_offset, reader.read_varint()?)?;
+                let end = checked_add(source_offset, length)?;
+                let data = rom.get(source_offset..end).ok_or(PatchError::Corrupt)?;
+                target.extend_from_slice(data);
+                source_offset = end;
+            }
+            //TargetCopy, byte by byte as the ranges may overlap
+            _ => {
+                target_offset = relative_offset(target_offset, reader.read_varint()?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or(PatchError::Corrupt)?;
+                    target.push(byte);
+                    target_offset = checked_add(target_offset, 1)?;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(PatchError::Corrupt);
+    }
+
+    check_target(&target, footer)?;
+    Ok(target)
+}
+
+fn relative_offset(offset: usize, data: usize) -> Result<usize, PatchError> {
+    let delta = data >> 1;
+    if data & 1 > 0 {
+        offset.checked_sub(delta).ok_or(PatchError::Corrupt)
+    } else {
+        checked_add(offset, delta)
+    }
+}
+
+//Offsets and lengths come from the patch, overflowing them means it is corrupt
+fn checked_add(offset: usize, length: usize) -> Result<usize, PatchError> {
+    offset.checked_add(length).ok_or(PatchError::Corrupt)
+}
+
+//Verify the patch and source checksums, returns the expected target checksum
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < FOOTER_SIZE + 4 {
+        return Err(PatchError::Corrupt);
+    }
+
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let source_crc = read_u32_le(&footer[0..4]);
+    let target_crc = read_u32_le(&footer[4..8]);
+    let patch_crc = read_u32_le(&footer[8..12]);
+
+    check_crc("patch", patch_crc, &patch[..patch.len() - 4])?;
+    check_crc("source ROM", source_crc, rom)?;
+
+    Ok(target_crc)
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    check_crc("patched ROM", expected, target)
+}
+
+fn check_crc(what: &'static str, expected: u32, data: &[u8]) -> Result<(), PatchError> {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    let actual = hasher.finalize();
+
+    if actual != expected {
+        return Err(PatchError::ChecksumMismatch {
+            what,
+            expected,
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        PatchReader { data, pos }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), PatchError> {
+        self.read_bytes(len).map(|_| ())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = checked_add(self.pos, len)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::Corrupt)?;
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, PatchError> {
+        self.read_bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn read_be(&mut self, len: usize) -> Result<usize, PatchError> {
+        let bytes = self.read_bytes(len)?;
+        Ok(bytes
+            .iter()
+            .fold(0, |word, byte| word << 8 | *byte as usize))
+    }
+
+    //UPS/BPS variable length number, each continuation adds an implicit offset
+    fn read_varint(&mut self) -> Result<usize, PatchError> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.read_u8()?;
+            data = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|x| data.checked_add(x))
+                .ok_or(PatchError::Corrupt)?;
+            if byte & 0x80 > 0 {
+                return Ok(data);
+            }
+
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Corrupt)?;
+            data = data.checked_add(shift).ok_or(PatchError::Corrupt)?;
+        }
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Corrupt => write!(f, "patch is corrupt"),
+            PatchError::SizeMismatch { expected, actual } => write!(
+                f,
+                "patch expects a {} bytes ROM, got {} bytes",
+                expected, actual
+            ),
+            PatchError::ChecksumMismatch {
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} checksum mismatch: expected 0x{:08X}, got 0x{:08X}",
+                what, expected, actual
+            ),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &[u8] = b"ABCDEFGH";
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn varint(mut data: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                bytes.push(0x80 | byte);
+                return bytes;
+            }
+            bytes.push(byte);
+            data -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc(source).to_le_bytes());
+        patch.extend_from_slice(&crc(target).to_le_bytes());
+        let patch_crc = crc(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips_records_rle_and_truncation() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, b'x', b'y']);
+        //RLE record growing the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, b'z']);
+        patch.extend_from_slice(IPS_EOF);
+        patch.extend_from_slice(&[0x00, 0x00, 0x09]);
+
+        assert_eq!(apply(SOURCE, &patch).unwrap(), b"AxyDEFzzz");
+    }
+
+    #[test]
+    fn ips_without_eof_is_corrupt() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, b'x']);
+
+        assert!(matches!(apply(SOURCE, &patch), Err(PatchError::Corrupt)));
+    }
+
+    fn ups_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(2));
+        patch.extend_from_slice(&[b'C' ^ b'x', 0x00]);
+        //Back to offset 8, past the terminating byte at 3
+        patch.extend(varint(4));
+        patch.extend_from_slice(&[b'Y', b'Z', 0x00]);
+        with_footer(patch, source, target)
+    }
+
+    #[test]
+    fn ups_xor_hunks() {
+        let patch = ups_patch(SOURCE, b"ABxDEFGHYZ");
+        assert_eq!(apply(SOURCE, &patch).unwrap(), b"ABxDEFGHYZ");
+    }
+
+    #[test]
+    fn ups_wrong_source_checksum() {
+        let patch = ups_patch(SOURCE, b"ABxDEFGHYZ");
+        match apply(b"ABCDEFGX", &patch) {
+            Err(PatchError::ChecksumMismatch {
+                what,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(what, "source ROM");
+                assert_eq!(expected, crc(SOURCE));
+                assert_eq!(actual, crc(b"ABCDEFGX"));
+            }
+            result => panic!("expected a checksum mismatch, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn ups_wrong_target_checksum() {
+        let patch = ups_patch(SOURCE, b"ABxDEFGHYY");
+        assert!(matches!(
+            apply(SOURCE, &patch),
+            Err(PatchError::ChecksumMismatch {
+                what: "patched ROM",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn ups_offset_overflow_is_corrupt() {
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend(varint(SOURCE.len()));
+        patch.extend(varint(SOURCE.len()));
+        for _ in 0..4 {
+            patch.extend(varint(1 << 62));
+            patch.push(0x00);
+        }
+        let patch = with_footer(patch, SOURCE, SOURCE);
+
+        assert!(matches!(apply(SOURCE, &patch), Err(PatchError::Corrupt)));
+    }
+
+    fn bps_action(kind: usize, length: usize) -> Vec<u8> {
+        varint((length - 1) << 2 | kind)
+    }
+
+    #[test]
+    fn bps_actions() {
+        let target = b"ABxDEFFFF";
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(varint(SOURCE.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(0));
+        //SourceRead, TargetRead, SourceCopy from 3 then an overlapping TargetCopy from 5
+        patch.extend(bps_action(0, 2));
+        patch.extend(bps_action(1, 1));
+        patch.push(b'x');
+        patch.extend(bps_action(2, 3));
+        patch.extend(varint(3 << 1));
+        patch.extend(bps_action(3, 3));
+        patch.extend(varint(5 << 1));
+        let patch = with_footer(patch, SOURCE, target);
+
+        assert_eq!(apply(SOURCE, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_copy_past_the_target_is_corrupt() {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(varint(SOURCE.len()));
+        patch.extend(varint(SOURCE.len()));
+        patch.extend(varint(0));
+        patch.extend(bps_action(0, 1));
+        patch.extend(bps_action(3, 1 << 40));
+        patch.extend(varint(0));
+        let patch = with_footer(patch, SOURCE, SOURCE);
+
+        assert!(matches!(apply(SOURCE, &patch), Err(PatchError::Corrupt)));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(matches!(
+            apply(SOURCE, b"NOPE"),
+            Err(PatchError::UnknownFormat)
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//Real time clock of MBC3 and HuC3 cartridges
+pub struct Rtc {
+    regs: [u8; RTC_REGS_NB],
+    latched: [u8; RTC_REGS_NB],
+    //Cycles since the seconds last changed
+    cycles: usize,
+}
+
+//Seconds, minutes, hours, day counter low bits, day counter high bit and flags
+const RTC_REGS_NB: usize = 5;
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAYS_LOW: usize = 3;
+const DAYS_HIGH: usize = 4;
+
+const DAYS_HIGH_MASK: u8 = 0b00000001;
+const HALT_MASK: u8 = 0b01000000;
+const DAY_CARRY_MASK: u8 = 0b10000000;
+
+//Bits kept when writing each register
+const REGS_MASKS: [u8; RTC_REGS_NB] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+//The clock has its own crystal, it runs at the same speed in double speed
+const CYCLES_PER_SECOND: usize = 4194304;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DAYS_MAX: u64 = 512;
+
+//BGB/VBA-M trailer: current and latched registers as u32 LE then the timestamp
+pub const RTC_TRAILER_SIZE: usize = 48;
+pub const RTC_TRAILER_SIZE_OLD: usize = 44;
+const RTC_TRAILER_REGS_SIZE: usize = RTC_REGS_NB * 2 * 4;
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc {
+            regs: [0; RTC_REGS_NB],
+            latched: [0; RTC_REGS_NB],
+            cycles: 0,
+        }
+    }
+
+    pub fn read(&self, reg: usize) -> u8 {
+        self.latched[reg]
+    }
+
+    //Writing the seconds restarts the current second
+    pub fn write(&mut self, reg: usize, data: u8) {
+        self.regs[reg] = data & REGS_MASKS[reg];
+        if reg == SECONDS {
+            self.cycles = 0;
+        }
+    }
+
+    pub fn latch(&mut self) {
+        self.latched = self.regs;
+    }
+
+    pub fn do_cycle(&mut self) {
+        if self.regs[DAYS_HIGH] & HALT_MASK > 0 {
+            return;
+        }
+
+        self.cycles += 1;
+        if self.cycles == CYCLES_PER_SECOND {
+            self.cycles = 0;
+            self.advance(1);
+        }
+    }
+
+    pub fn advance(&mut self, seconds: u64) {
+        if self.regs[DAYS_HIGH] & HALT_MASK > 0 || seconds == 0 {
+            return;
+        }
+
+        let days =
+            ((self.regs[DAYS_HIGH] & DAYS_HIGH_MASK) as u64) << 8 | self.regs[DAYS_LOW] as u64;
+        let total = self.regs[SECONDS] as u64
+            + self.regs[MINUTES] as u64 * 60
+            + self.regs[HOURS] as u64 * 60 * 60
+            + days * SECONDS_PER_DAY
+            + seconds;
+
+        let days = total / SECONDS_PER_DAY;
+        self.regs[SECONDS] = (total % 60) as u8;
+        self.regs[MINUTES] = (total / 60 % 60) as u8;
+        self.regs[HOURS] = (total / (60 * 60) % 24) as u8;
+        self.regs[DAYS_LOW] = (days % DAYS_MAX) as u8;
+
+        self.regs[DAYS_HIGH] &= !DAYS_HIGH_MASK;
+        self.regs[DAYS_HIGH] |= ((days % DAYS_MAX) >> 8) as u8;
+        if days >= DAYS_MAX {
+            self.regs[DAYS_HIGH] |= DAY_CARRY_MASK;
+        }
+    }
+
+    pub fn to_trailer(&self) -> Vec<u8> {
+        self.to_trailer_at(unix_time())
+    }
+
+    fn to_trailer_at(&self, now: u64) -> Vec<u8> {
+        let mut trailer = Vec::with_capacity(RTC_TRAILER_SIZE);
+        for reg in self.regs.iter().chain(self.latched.iter()) {
+            trailer.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        trailer.extend_from_slice(&now.to_le_bytes());
+
+        trailer
+    }
+
+    //Restore the registers and advance the clock by the time spent powered off
+    pub fn load_trailer(&mut self, trailer: &[u8]) -> bool {
+        self.load_trailer_at(trailer, unix_time())
+    }
+
+    fn load_trailer_at(&mut self, trailer: &[u8], now: u64) -> bool {
+        let timestamp = match trailer.len() {
+            RTC_TRAILER_SIZE => read_u64_le(&trailer[RTC_TRAILER_REGS_SIZE..]),
+            RTC_TRAILER_SIZE_OLD => read_u32_le(&trailer[RTC_TRAILER_REGS_SIZE..]) as u64,
+            _ => return false,
+        };
+
+        for i in 0..RTC_REGS_NB {
+            self.regs[i] = read_u32_le(&trailer[i * 4..]) as u8;
+            self.latched[i] = read_u32_le(&trailer[(RTC_REGS_NB + i) * 4..]) as u8;
+        }
+
+        self.advance(now.saturating_sub(timestamp));
+        true
+    }
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //1 day, 1 hour, 1 minute and 1 second
+    const ELAPSED: u64 = SECONDS_PER_DAY + 60 * 60 + 60 + 1;
+    const SAVED_AT: u64 = 1_600_000_000;
+
+    fn clock(regs: [u8; RTC_REGS_NB]) -> Rtc {
+        let mut rtc = Rtc::new();
+        for (reg, data) in regs.iter().enumerate() {
+            rtc.write(reg, *data);
+        }
+        rtc.latch();
+        rtc
+    }
+
+    #[test]
+    fn trailer_round_trip() {
+        let saved = clock([12, 34, 5, 0xFF, 0x01]);
+        let trailer = saved.to_trailer_at(SAVED_AT);
+        assert_eq!(trailer.len(), RTC_TRAILER_SIZE);
+
+        let mut rtc = Rtc::new();
+        assert!(rtc.load_trailer_at(&trailer, SAVED_AT));
+        assert_eq!(rtc.regs, saved.regs);
+        assert_eq!(rtc.latched, saved.latched);
+    }
+
+    #[test]
+    fn load_advances_by_elapsed_time() {
+        let trailer = clock([12, 34, 5, 0xFF, 0x00]).to_trailer_at(SAVED_AT);
+
+        let mut rtc = Rtc::new();
+        assert!(rtc.load_trailer_at(&trailer, SAVED_AT + ELAPSED));
+        assert_eq!(rtc.regs, [13, 35, 6, 0x00, 0x01]);
+        //Latched registers only change on the next latch
+        assert_eq!(rtc.latched, [12, 34, 5, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn load_old_trailer_with_32_bit_timestamp() {
+        let mut trailer = clock([59, 59, 23, 0xFF, 0x01]).to_trailer_at(SAVED_AT);
+        trailer.truncate(RTC_TRAILER_REGS_SIZE);
+        trailer.extend_from_slice(&(SAVED_AT as u32).to_le_bytes());
+        assert_eq!(trailer.len(), RTC_TRAILER_SIZE_OLD);
+
+        //Day 511 rolls over to 0 and sets the day carry
+        let mut rtc = Rtc::new();
+        assert!(rtc.load_trailer_at(&trailer, SAVED_AT + ELAPSED));
+        assert_eq!(rtc.regs, [0, 1, 1, 0x01, DAY_CARRY_MASK]);
+    }
+
+    #[test]
+    fn halted_clock_does_not_advance() {
+        let trailer = clock([12, 34, 5, 0x00, HALT_MASK]).to_trailer_at(SAVED_AT);
+
+        let mut rtc = Rtc::new();
+        assert!(rtc.load_trailer_at(&trailer, SAVED_AT + ELAPSED));
+        assert_eq!(rtc.regs, [12, 34, 5, 0x00, HALT_MASK]);
+    }
+
+    #[test]
+    fn bad_trailer_size() {
+        let mut rtc = Rtc::new();
+        assert!(!rtc.load_trailer_at(&[0; RTC_TRAILER_SIZE - 1], SAVED_AT));
+    }
+
+    #[test]
+    fn cycles_advance_the_seconds() {
+        let mut rtc = clock([59, 0, 0, 0x00, 0x00]);
+        for _ in 0..CYCLES_PER_SECOND {
+            rtc.do_cycle();
+        }
+        assert_eq!(rtc.regs[..3], [0, 1, 0]);
+    }
+}
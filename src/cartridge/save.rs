@@ -0,0 +1,184 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::rtc::RTC_TRAILER_SIZE;
+use crate::cartridge::Cartridge;
+
+//Raw RAM dump next to the ROM, same layout as other emulators.
+//Cartridges with a clock append the BGB/VBA-M RTC trailer after the RAM.
+pub struct SaveFile {
+    path: PathBuf,
+    saved: Vec<u8>,
+}
+
+impl SaveFile {
+    pub fn new(rom_path: &Path) -> Self {
+        //Game.gb.gz saves to Game.sav like Game.gb
+        let rom_path = match rom_path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => rom_path.with_extension(""),
+            _ => rom_path.to_path_buf(),
+        };
+
+        SaveFile {
+            path: rom_path.with_extension("sav"),
+            saved: Vec::new(),
+        }
+    }
+
+    pub fn load(&mut self, cartridge: &mut dyn Cartridge) -> io::Result<()> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                let ram_len = cartridge.ram().len().min(data.len());
+                cartridge.load_ram(&data[..ram_len]);
+                if let Some(rtc) = cartridge.rtc_mut() {
+                    if !rtc.load_trailer(&data[ram_len..]) {
+                        println!("No valid RTC data in save file, clock reset");
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        self.saved = save_data(cartridge);
+        Ok(())
+    }
+
+    pub fn flush(&mut self, cartridge: &dyn Cartridge) -> io::Result<()> {
+        let data = save_data(cartridge);
+        if without_timestamp(cartridge, &data) == without_timestamp(cartridge, &self.saved) {
+            return Ok(());
+        }
+
+        write_atomic(&self.path, &data)?;
+        self.saved = data;
+        Ok(())
+    }
+}
+
+fn save_data(cartridge: &dyn Cartridge) -> Vec<u8> {
+    let mut data = cartridge.ram().to_vec();
+    if let Some(rtc) = cartridge.rtc() {
+        data.extend(rtc.to_trailer());
+    }
+
+    data
+}
+
+//The timestamp changes every second, it alone is no reason to write the file
+fn without_timestamp<'a>(cartridge: &dyn Cartridge, data: &'a [u8]) -> &'a [u8] {
+    match cartridge.rtc() {
+        Some(_) if data.len() >= RTC_TRAILER_SIZE => &data[..data.len() - 8],
+        _ => data,
+    }
+}
+
+//Write to a temporary file then rename it, a crash never leaves a partial save
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("sav.tmp");
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::mbc3::Mbc3;
+    use crate::cartridge::mbc5::Mbc5;
+
+    const RAM_SIZE: usize = 0x2000;
+
+    //Directory of its own for each test, removed with its content
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("gb-rs-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn mbc5() -> Mbc5 {
+        let mut mbc5 = Mbc5::new(vec![0; 0x8000], RAM_SIZE, false, true);
+        mbc5.write_rom(0x0000, 0x0A);
+        mbc5
+    }
+
+    #[test]
+    fn ram_round_trip() {
+        let dir = TempDir::new("round-trip");
+        let rom_path = dir.0.join("Game.gb.gz");
+
+        let mut cartridge = mbc5();
+        let mut save_file = SaveFile::new(&rom_path);
+        save_file.load(&mut cartridge).unwrap();
+        cartridge.write_ram(0x0000, 0x12);
+        cartridge.write_ram(RAM_SIZE as u16 - 1, 0x34);
+        save_file.flush(&cartridge).unwrap();
+
+        let sav_path = dir.0.join("Game.sav");
+        assert_eq!(fs::read(&sav_path).unwrap().len(), RAM_SIZE);
+        assert!(!dir.0.join("Game.sav.tmp").exists());
+
+        let mut loaded = mbc5();
+        SaveFile::new(&rom_path).load(&mut loaded).unwrap();
+        assert_eq!(loaded.read_ram(0x0000), 0x12);
+        assert_eq!(loaded.read_ram(RAM_SIZE as u16 - 1), 0x34);
+    }
+
+    #[test]
+    fn unchanged_ram_is_not_written() {
+        let dir = TempDir::new("unchanged");
+        let mut cartridge = mbc5();
+        let mut save_file = SaveFile::new(&dir.0.join("Game.gb"));
+        save_file.load(&mut cartridge).unwrap();
+
+        save_file.flush(&cartridge).unwrap();
+        assert!(!dir.0.join("Game.sav").exists());
+
+        cartridge.write_ram(0x0000, 0x01);
+        save_file.flush(&cartridge).unwrap();
+        fs::remove_file(dir.0.join("Game.sav")).unwrap();
+        save_file.flush(&cartridge).unwrap();
+        assert!(!dir.0.join("Game.sav").exists());
+    }
+
+    #[test]
+    fn clock_is_saved_after_the_ram() {
+        let dir = TempDir::new("clock");
+        let rom_path = dir.0.join("Game.gbc");
+
+        let mut cartridge = Mbc3::new(vec![0; 0x8000], RAM_SIZE, true, true);
+        let mut save_file = SaveFile::new(&rom_path);
+        save_file.load(&mut cartridge).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x0A);
+        cartridge.write_ram(0x0000, 5);
+        save_file.flush(&cartridge).unwrap();
+
+        let data = fs::read(dir.0.join("Game.sav")).unwrap();
+        assert_eq!(data.len(), RAM_SIZE + RTC_TRAILER_SIZE);
+
+        let mut loaded = Mbc3::new(vec![0; 0x8000], RAM_SIZE, true, true);
+        SaveFile::new(&rom_path).load(&mut loaded).unwrap();
+        loaded.write_rom(0x0000, 0x0A);
+        loaded.write_rom(0x6000, 0x00);
+        loaded.write_rom(0x6000, 0x01);
+        loaded.write_rom(0x4000, 0x0A);
+        assert_eq!(loaded.read_ram(0x0000), 5);
+    }
+}
@@ -0,0 +1,149 @@
+use minifb::{Scale, Window, WindowOptions};
+
+use crate::apu::ChannelState;
+
+//Piano roll of the 4 channels scrolling by a pixel per frame, then a column per channel
+//with its duty cycle on top and its volume below
+const ROLL_WIDTH: usize = 192;
+const COLUMN_WIDTH: usize = 16;
+const WIDTH: usize = ROLL_WIDTH + COLUMN_WIDTH * 4;
+const HEIGHT: usize = 144;
+
+//Tone channels span 32 Hz to 8192 Hz, 18 pixels per octave
+const ROLL_LOW_OCTAVE: f64 = 5.0;
+const ROLL_OCTAVES: f64 = 8.0;
+//The noise LFSR clocks from 2 Hz to 512 kHz
+const NOISE_LOW_OCTAVE: f64 = 1.0;
+const NOISE_OCTAVES: f64 = 18.0;
+
+const DUTY_HEIGHT: usize = 16;
+
+const BACKGROUND: u32 = 0x00101010;
+const OCTAVE_LINE: u32 = 0x00202020;
+const MUTED: u32 = 0x00606060;
+const COLORS: [u32; 4] = [0x00FF4040, 0x0040FF40, 0x004080FF, 0x00FFFF40];
+
+pub struct ChannelView {
+    window: Window,
+    buffer: Vec<u32>,
+}
+
+impl ChannelView {
+    pub fn new() -> Self {
+        let options = WindowOptions {
+            scale: Scale::X2,
+            ..WindowOptions::default()
+        };
+        let mut view = ChannelView {
+            window: Window::new("GB-rs channels", WIDTH, HEIGHT, options).unwrap_or_else(|e| {
+                panic!("{}", e);
+            }),
+            buffer: vec![BACKGROUND; WIDTH * HEIGHT],
+        };
+        for y in 0..HEIGHT {
+            view.buffer[y * WIDTH..y * WIDTH + ROLL_WIDTH]
+                .copy_from_slice(&[background(y); ROLL_WIDTH]);
+        }
+        view
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    //Call once per frame
+    pub fn update(&mut self, states: &[ChannelState; 4]) {
+        for y in 0..HEIGHT {
+            let row = &mut self.buffer[y * WIDTH..y * WIDTH + ROLL_WIDTH];
+            row.copy_within(1.., 0);
+            row[ROLL_WIDTH - 1] = background(y);
+        }
+
+        for (i, state) in states.iter().enumerate() {
+            if !state.enabled || state.volume == 0 {
+                continue;
+            }
+
+            let (low, octaves) = if i == 3 {
+                (NOISE_LOW_OCTAVE, NOISE_OCTAVES)
+            } else {
+                (ROLL_LOW_OCTAVE, ROLL_OCTAVES)
+            };
+            let position = (state.frequency.log2() - low) / octaves;
+            if !(0.0..1.0).contains(&position) {
+                continue;
+            }
+
+            let y = HEIGHT - 1 - (position * HEIGHT as f64) as usize;
+            self.buffer[y * WIDTH + ROLL_WIDTH - 1] = color(i, state);
+        }
+
+        for (i, state) in states.iter().enumerate() {
+            self.draw_column(i, state);
+        }
+
+        self.window.update_with_buffer(&self.buffer).unwrap();
+    }
+
+    fn draw_column(&mut self, channel: usize, state: &ChannelState) {
+        let x0 = ROLL_WIDTH + channel * COLUMN_WIDTH;
+        for y in 0..HEIGHT {
+            for x in x0..x0 + COLUMN_WIDTH {
+                self.buffer[y * WIDTH + x] = BACKGROUND;
+            }
+        }
+
+        let color = if state.audible {
+            COLORS[channel]
+        } else {
+            MUTED
+        };
+
+        //One period of the pulse waveform, high part first
+        if let Some(duty) = state.duty {
+            let high = (duty * (COLUMN_WIDTH - 4) as f32) as usize;
+            for x in 0..COLUMN_WIDTH - 4 {
+                let y = if x < high { 3 } else { DUTY_HEIGHT - 4 };
+                self.buffer[y * WIDTH + x0 + 2 + x] = color;
+            }
+        }
+
+        let volume = if state.enabled {
+            state.volume as usize
+        } else {
+            0
+        };
+        let bar = volume * (HEIGHT - DUTY_HEIGHT) / 15;
+        for y in HEIGHT - bar..HEIGHT {
+            for x in x0 + 2..x0 + COLUMN_WIDTH - 2 {
+                self.buffer[y * WIDTH + x] = color;
+            }
+        }
+    }
+}
+
+//Marks every octave of the tone channels
+fn background(y: usize) -> u32 {
+    let octave_height = HEIGHT / ROLL_OCTAVES as usize;
+    if (HEIGHT - 1 - y) % octave_height == 0 {
+        OCTAVE_LINE
+    } else {
+        BACKGROUND
+    }
+}
+
+//Louder notes are brighter
+fn color(channel: usize, state: &ChannelState) -> u32 {
+    let base = if state.audible {
+        COLORS[channel]
+    } else {
+        MUTED
+    };
+    let scale = (state.volume as u32 + 1) * 16;
+    let mut color = 0;
+    for shift in [0, 8, 16].iter() {
+        let component = (base >> shift) & 0xFF;
+        color |= (component * scale / 256) << shift;
+    }
+    color
+}
@@ -1,8 +1,27 @@
+use std::fmt;
+use std::io;
 use std::path;
-use std::{thread, time};
 
+use crate::apu::{Channel, ChannelState};
+use crate::audio::AudioSink;
+use crate::cartridge::{CartridgeError, RumbleCallback};
+use crate::debugger::Breakpoint;
+use crate::lcd::VideoSink;
+use crate::link::Transport;
 use crate::mmu::Mmu;
+use crate::model::Model;
+use crate::palette::{ColorCorrection, DmgPalette};
 use crate::regs::*;
+use crate::serial::SerialDevice;
+
+//Logs each instruction executed, off unless turned on from the debugger
+macro_rules! trace {
+    ($cpu:ident; $($arg:tt)*) => {
+        if $cpu.trace {
+            println!($($arg)*);
+        }
+    };
+}
 
 pub struct Cpu {
     regs: Registers,
@@ -11,63 +30,332 @@ pub struct Cpu {
     interrupts: bool,
     cycles: u8,
 
+    frame_cycles: usize,
+    frames: u64,
+    frame_limit: Option<u64>,
+    stop_condition: Option<StopCondition>,
+    breakpoints: Vec<Breakpoint>,
+    trace: bool,
+
     mmu: Mmu,
 }
 
+pub type StopCondition = Box<dyn FnMut(&Registers) -> bool>;
+
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    WindowClosed,
+    FrameLimit,
+    Condition,
+    Breakpoint,
+    //Left from the debugger
+    Quit,
+    //PC is left on the opcode, CB prefixed opcodes are 0xCBxx
+    UnimplementedOpcode { pc: u16, opcode: u16 },
+}
+
 struct ModeChangeFlags {
-    change_intrpt_mode_on_next_instr: bool,
+    //EI enables interrupts after the instruction following it
+    enable_intrpt_after_next_instr: bool,
 }
 
-const CYCLE_TIME_MICRO: u64 = 5;
+//VBlank, then STAT, timer, serial and joypad 8 bytes apart
+const INTERRUPT_VECTORS: u8 = 0x40;
+
+pub const CYCLES_PER_FRAME: usize = 70224;
 
 impl Cpu {
-    pub fn new(path: &path::Path) -> Self {
-        Cpu {
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        Ok(Cpu {
             regs: Registers::new(),
             mode_flags: ModeChangeFlags::new(),
 
-            interrupts: true,
+            interrupts: false,
             cycles: 0,
 
-            mmu: Mmu::new(path),
+            frame_cycles: 0,
+            frames: 0,
+            frame_limit: None,
+            stop_condition: None,
+            breakpoints: Vec::new(),
+            trace: false,
+
+            mmu: Mmu::new(rom)?,
+        })
+    }
+
+    //Run until the window closes, the frame limit is reached or the stop condition is met
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            if let Some(reason) = self.run_frame() {
+                return reason;
+            }
+        }
+    }
+
+    //Run up to the end of the current frame, front ends pace emulation between calls
+    pub fn run_frame(&mut self) -> Option<StopReason> {
+        let frame = self.frames;
+        while self.frames == frame {
+            if self.at_instruction_start() {
+                if let Some(reason) = self.check_stop() {
+                    return Some(reason);
+                }
+            }
+
+            if let Some(reason) = self.cycle() {
+                return Some(reason);
+            }
         }
+
+        None
     }
 
-    pub fn run(&mut self) {
+    //Run the next instruction and the cycles it takes, breakpoints are not checked
+    pub fn step(&mut self) -> Option<StopReason> {
+        let mut started = false;
         loop {
-            self.mmu.do_cycle();
+            started |= self.at_instruction_start();
+            if let Some(reason) = self.cycle() {
+                return Some(reason);
+            }
+            if started && self.at_instruction_start() {
+                return None;
+            }
+        }
+    }
 
-            if self.cycles != 0 {
-                // self.cycle_wait();
-                self.cycles -= 1;
-                continue;
+    //VRAM DMA holds the CPU, time still runs
+    fn at_instruction_start(&self) -> bool {
+        self.cycles == 0 && !self.mmu.is_dma_stalling()
+    }
+
+    fn check_stop(&mut self) -> Option<StopReason> {
+        if let Some(condition) = self.stop_condition.as_mut() {
+            if condition(&self.regs) {
+                return Some(StopReason::Condition);
             }
+        }
+        if self.is_at_breakpoint() {
+            return Some(StopReason::Breakpoint);
+        }
+
+        None
+    }
 
-            // End of prototype
-            if self.regs.PC == 0x100 {
-                println!("");
-                self.dump();
-                return;
+    //One cycle, the next instruction or interrupt starts once the previous one is done
+    fn cycle(&mut self) -> Option<StopReason> {
+        if self.at_instruction_start() {
+            self.cycles = match self.service_interrupt() {
+                Some(cycles) => cycles,
+                None => {
+                    let instr = self.mmu.read(self.regs.PC);
+                    self.regs.inc_PC();
+                    match self.exec_instr(instr) {
+                        Ok(cycles) => cycles,
+                        Err(reason) => return Some(reason),
+                    }
+                }
+            };
+        }
+
+        //Frames last as long in double speed, the CPU just runs twice as many cycles
+        let normal_speed_cycle = self.mmu.do_cycle();
+        self.cycles = self.cycles.saturating_sub(1);
+        if normal_speed_cycle {
+            self.frame_cycles += 1;
+            if self.frame_cycles == CYCLES_PER_FRAME {
+                return self.end_frame();
             }
+        }
+
+        None
+    }
+
+    fn end_frame(&mut self) -> Option<StopReason> {
+        self.frame_cycles = 0;
+        self.frames += 1;
+        self.mmu.apu.end_frame();
 
-            let instr = self.mmu.read(self.regs.PC);
-            self.regs.inc_PC();
-            self.cycles = self.exec_instr(instr);
+        if !self.mmu.ppu.is_open() {
+            return Some(StopReason::WindowClosed);
         }
+        if self.frame_limit.map_or(false, |limit| self.frames >= limit) {
+            return Some(StopReason::FrameLimit);
+        }
+
+        None
     }
 
-    fn cycle_wait(&self) {
-        let wait = time::Duration::from_micros(CYCLE_TIME_MICRO);
-        thread::sleep(wait);
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
     }
 
-    fn exec_instr(&mut self, instr: u8) -> u8 {
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        if index < self.breakpoints.len() {
+            Some(self.breakpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn is_at_breakpoint(&self) -> bool {
+        let pc = self.regs.PC;
+        let bank = self.mmu.bank_at(pc);
+        self.breakpoints.iter().any(|breakpoint| {
+            breakpoint.addr == pc && (breakpoint.bank.is_none() || breakpoint.bank == bank)
+        })
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace
+    }
+
+    pub fn regs(&self) -> &Registers {
+        &self.regs
+    }
+
+    pub fn regs_mut(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    //Memory as the CPU sees it, None where reading is not implemented
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        self.mmu.peek(addr)
+    }
+
+    pub fn bank_at(&self, addr: u16) -> Option<u16> {
+        self.mmu.bank_at(addr)
+    }
+
+    //Headless runs stop after this many frames
+    pub fn set_frame_limit(&mut self, frames: Option<u64>) {
+        self.frame_limit = frames;
+    }
+
+    //Checked before each instruction, run returns once it is true
+    pub fn set_stop_condition(&mut self, condition: Option<StopCondition>) {
+        self.stop_condition = condition;
+    }
+
+    pub fn set_video_sink(&mut self, sink: Box<dyn VideoSink>) {
+        self.mmu.ppu.set_video_sink(sink);
+    }
+
+    pub fn set_dmg_palette(&mut self, dmg_palette: DmgPalette) {
+        self.mmu.ppu.set_dmg_palette(dmg_palette);
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.mmu.ppu.set_color_correction(correction);
+    }
+
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.mmu.apu.set_audio_sink(sink);
+    }
+
+    //Writes the sound output to a .wav file, with a file per channel when stems is set
+    pub fn start_recording(&mut self, path: &path::Path, stems: bool) -> io::Result<()> {
+        self.mmu.apu.start_recording(path, stems)
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        self.mmu.apu.stop_recording()
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.mmu.apu.set_channel_muted(channel, muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.mmu.apu.is_channel_muted(channel)
+    }
+
+    pub fn set_solo(&mut self, solo: Option<Channel>) {
+        self.mmu.apu.set_solo(solo);
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.mmu.apu.solo()
+    }
+
+    pub fn channel_states(&self) -> [ChannelState; 4] {
+        self.mmu.apu.channel_states()
+    }
+
+    //Plugs a link cable into the serial port, see link::listen and link::connect
+    pub fn connect_link(&mut self, transport: Transport) {
+        self.mmu.serial.connect(transport);
+    }
+
+    //Accessory on the serial port instead of a link cable
+    pub fn attach_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.mmu.serial.attach_device(device);
+    }
+
+    //Bytes sent on the serial port since the last call
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.mmu.serial.take_output()
+    }
+
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) {
+        self.mmu.set_boot_rom(data);
+    }
+
+    //Forces the model instead of the one the cartridge header asks for
+    pub fn set_model(&mut self, model: Model) {
+        self.mmu.set_model(model);
+    }
+
+    pub fn model(&self) -> Model {
+        self.mmu.model()
+    }
+
+    pub fn screen_size(&self) -> (usize, usize) {
+        self.mmu.screen_size()
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.mmu.is_cgb_mode()
+    }
+
+    //Start directly at 0x0100 in the post-boot state of the model
+    pub fn skip_boot(&mut self) {
+        let header_checksum = self.mmu.read(0x014D);
+        self.mmu.skip_boot();
+        self.regs = Registers::after_boot(self.mmu.model(), header_checksum);
+    }
+
+    pub fn attach_save_file(&mut self, rom_path: &path::Path) -> io::Result<()> {
+        self.mmu.attach_save_file(rom_path)
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        self.mmu.save()
+    }
+
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.mmu.set_rumble_callback(callback);
+    }
+
+    fn exec_instr(&mut self, instr: u8) -> Result<u8, StopReason> {
         let addr = self.regs.PC - 1;
 
         let cycles = match instr {
             //NOP
             0x00 => {
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
                     addr, instr, 4, "NOP"
                 );
@@ -78,7 +366,7 @@ impl Cpu {
             0x04 => {
                 self.regs.B = self.inc_u8(self.regs.B);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "INC", "B"
                 );
@@ -89,7 +377,7 @@ impl Cpu {
             0x05 => {
                 self.regs.B = self.dec_u8(self.regs.B);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "DEC", "B"
                 );
@@ -101,7 +389,7 @@ impl Cpu {
                 let data = self.get_imu8();
                 self.regs.B = data;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "B,", data
                 );
@@ -112,7 +400,7 @@ impl Cpu {
             0x0C => {
                 self.regs.C = self.inc_u8(self.regs.C);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "INC", "C"
                 );
@@ -123,7 +411,7 @@ impl Cpu {
             0x0D => {
                 self.regs.C = self.dec_u8(self.regs.C);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "DEC", "C"
                 );
@@ -135,7 +423,7 @@ impl Cpu {
                 let data = self.get_imu8();
                 self.regs.C = data;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "C,", data
                 );
@@ -147,19 +435,35 @@ impl Cpu {
                 let offset = self.get_imu8();
                 self.regs.A = self.mmu.read(0xFF00 + offset as u16);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 12, "LDH", "A,", offset
                 );
                 12
             }
 
+            //STOP, only the CGB speed switch is emulated
+            0x10 => {
+                self.get_imu8();
+                let switched = self.mmu.stop();
+
+                trace!(self;
+                    "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}{}",
+                    addr,
+                    instr,
+                    4,
+                    "STOP",
+                    if switched { " (speed switch)" } else { "" }
+                );
+                4
+            }
+
             //LD DE imu16
             0x11 => {
                 let word = self.get_imu16();
                 self.regs.set_DE(word);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 12, "LD", "DE,", word
                 );
@@ -171,7 +475,7 @@ impl Cpu {
                 let inc = self.inc_u16(self.regs.DE());
                 self.regs.set_DE(inc);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 8, "INC", "DE"
                 );
@@ -182,7 +486,7 @@ impl Cpu {
             0x15 => {
                 self.regs.D = self.dec_u8(self.regs.D);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "DEC", "D"
                 );
@@ -194,7 +498,7 @@ impl Cpu {
                 let data = self.get_imu8();
                 self.regs.D = data;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "D,", data
                 );
@@ -205,7 +509,7 @@ impl Cpu {
             0x17 => {
                 self.regs.A = self.rl(self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
                     addr, instr, 4, "RLA"
                 );
@@ -218,7 +522,7 @@ impl Cpu {
                 let jmp_addr = ((self.regs.PC as i32) + offset as i32) as u16;
                 self.regs.PC = jmp_addr;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:04X}",
                     addr, instr, 8, "JR", "NZ", jmp_addr
                 );
@@ -229,7 +533,7 @@ impl Cpu {
             0x1A => {
                 self.regs.A = self.mmu.read(self.regs.DE());
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 8, "LD", "A,", "DE"
                 );
@@ -240,7 +544,7 @@ impl Cpu {
             0x1D => {
                 self.regs.E = self.dec_u8(self.regs.E);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "DEC", "E"
                 );
@@ -252,7 +556,7 @@ impl Cpu {
                 let data = self.get_imu8();
                 self.regs.E = data;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "E,", data
                 );
@@ -267,7 +571,7 @@ impl Cpu {
                     self.regs.PC = jmp_addr;
                 }
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:04X}",
                     addr, instr, 8, "JR", "NZ", jmp_addr
                 );
@@ -279,7 +583,7 @@ impl Cpu {
                 let data = self.get_imu16();
                 self.regs.set_HL(data);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 12, "LD", "HL,", data
                 );
@@ -292,7 +596,7 @@ impl Cpu {
                 self.mmu.write(addr_write, self.regs.A);
                 self.regs.set_HL(addr_write + 1);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 8, "LD", "(HL+),", "A"
                 );
@@ -304,7 +608,7 @@ impl Cpu {
                 let inc = self.inc_u16(self.regs.HL());
                 self.regs.set_HL(inc);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 8, "INC", "HL"
                 );
@@ -315,7 +619,7 @@ impl Cpu {
             0x24 => {
                 self.regs.H = self.inc_u8(self.regs.H);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "INC", "H"
                 );
@@ -331,7 +635,7 @@ impl Cpu {
                     self.regs.PC = jmp_addr;
                 }
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "JR", "Z", offset
                 );
@@ -342,7 +646,7 @@ impl Cpu {
             0x2E => {
                 self.regs.L = self.get_imu8();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "L,", self.regs.L
                 );
@@ -353,7 +657,7 @@ impl Cpu {
             0x31 => {
                 self.regs.SP = self.get_imu16();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 12, "LD", "SP,", self.regs.SP
                 );
@@ -366,7 +670,7 @@ impl Cpu {
                 self.mmu.write(addr_write, self.regs.A);
                 self.regs.set_HL(addr_write - 1);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} 0x{:04X}, {}",
                     addr, instr, 8, "LDD", addr_write, "A"
                 );
@@ -377,7 +681,7 @@ impl Cpu {
             0x3D => {
                 self.regs.A = self.dec_u8(self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "DEC", "A"
                 );
@@ -388,7 +692,7 @@ impl Cpu {
             0x3E => {
                 self.regs.A = self.get_imu8();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} 0x{:X}",
                     addr, instr, 8, "LD", "A,", self.regs.A
                 );
@@ -399,7 +703,7 @@ impl Cpu {
             0x42 => {
                 self.regs.B = self.regs.C;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "B,", "C"
                 );
@@ -410,7 +714,7 @@ impl Cpu {
             0x4F => {
                 self.regs.C = self.regs.A;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "C,", "A"
                 );
@@ -421,7 +725,7 @@ impl Cpu {
             0x57 => {
                 self.regs.D = self.regs.A;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "D,", "A"
                 );
@@ -432,7 +736,7 @@ impl Cpu {
             0x67 => {
                 self.regs.H = self.regs.A;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "H,", "A"
                 );
@@ -443,7 +747,7 @@ impl Cpu {
             0x77 => {
                 self.mmu.write(self.regs.HL(), self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 8, "LD", "(HL),", "A"
                 );
@@ -454,7 +758,7 @@ impl Cpu {
             0x78 => {
                 self.regs.A = self.regs.B;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "A,", "B"
                 );
@@ -464,7 +768,7 @@ impl Cpu {
             0x7B => {
                 self.regs.A = self.regs.E;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "A,", "E"
                 );
@@ -475,7 +779,7 @@ impl Cpu {
             0x7C => {
                 self.regs.A = self.regs.H;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "A,", "H"
                 );
@@ -486,7 +790,7 @@ impl Cpu {
             0x7D => {
                 self.regs.A = self.regs.L;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 4, "LD", "A,", "L"
                 );
@@ -498,7 +802,7 @@ impl Cpu {
                 let byte = self.mmu.read(self.regs.HL());
                 self.regs.A = self.add(self.regs.A, byte);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}, 0x{:X}",
                     addr, instr, 8, "ADD", "A", byte
                 );
@@ -509,7 +813,7 @@ impl Cpu {
             0x90 => {
                 self.regs.A = self.sub(self.regs.A, self.regs.B);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}, {}",
                     addr, instr, 4, "SUB", "A", "B"
                 );
@@ -520,7 +824,7 @@ impl Cpu {
             0xAF => {
                 self.xor(self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 4, "XOR", "A"
                 );
@@ -532,7 +836,7 @@ impl Cpu {
                 let byte = self.mmu.read(self.regs.HL());
                 self.sub(self.regs.A, byte);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 8, "CP", "(HL)"
                 );
@@ -544,7 +848,7 @@ impl Cpu {
                 let word = self.stack_pop_u16();
                 self.regs.set_BC(word);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 12, "POP", "BC"
                 );
@@ -555,7 +859,7 @@ impl Cpu {
             0xC3 => {
                 self.regs.PC = self.get_imu16();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} 0x{:04X}",
                     addr, instr, 16, "JMP", self.regs.PC
                 );
@@ -565,7 +869,7 @@ impl Cpu {
             //PUSH BC
             0xC5 => {
                 self.stack_push_u16(self.regs.BC());
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}",
                     addr, instr, 16, "PUSH", "BC"
                 );
@@ -576,24 +880,36 @@ impl Cpu {
             0xC9 => {
                 self.ret();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
                     addr, instr, 8, "RET"
                 );
                 8
             }
 
+            //RETI, interrupts are enabled right away
+            0xD9 => {
+                self.ret();
+                self.interrupts = true;
+
+                trace!(self;
+                    "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
+                    addr, instr, 16, "RETI"
+                );
+                16
+            }
+
             //CB Prefix
             0xCB => {
                 let instr = self.get_imu8();
-                self.exec_prefix_instr(instr) + 4
+                self.exec_prefix_instr(instr)? + 4
             }
 
             //Call
             0xCD => {
                 self.call();
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} 0x{:04X}",
                     addr, instr, 12, "CALL", self.regs.PC
                 );
@@ -604,7 +920,7 @@ impl Cpu {
             0xDF => {
                 self.rst(0x18);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} 0x{:X}",
                     addr, instr, 32, "RST", 0x18
                 );
@@ -616,7 +932,7 @@ impl Cpu {
                 let byte = self.get_imu8();
                 self.mmu.write(0xFF00 + byte as u16, self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} ({} 0x{:X}), {}",
                     addr, instr, 12, "LD", "0xFF00 + ", byte, "A"
                 );
@@ -627,7 +943,7 @@ impl Cpu {
             0xE2 => {
                 self.mmu.write(0xFF00 + self.regs.C as u16, self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {} {}",
                     addr, instr, 8, "LD", "(0xFF00 + C),", "A"
                 );
@@ -639,30 +955,40 @@ impl Cpu {
                 let addr_write = self.get_imu16();
                 self.mmu.write(addr_write, self.regs.A);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} (0x{:X}), {}",
                     addr, instr, 16, "LD", addr_write, "A"
                 );
                 16
             }
 
-            //DI
+            //DI, also cancels a pending EI
             0xF3 => {
-                //Mode flag set at end of func
+                self.interrupts = false;
+                self.mode_flags.enable_intrpt_after_next_instr = false;
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
                     addr, instr, 4, "DI"
                 );
                 4
             }
 
+            //EI, mode flag set at end of func
+            0xFB => {
+                trace!(self;
+                    "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{}",
+                    addr, instr, 4, "EI"
+                );
+                4
+            }
+
             //CP A #
             0xFE => {
                 let byte = self.get_imu8();
                 self.sub(self.regs.A, byte);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:0x{:X}\t\tTime:{}\t\t{} {}, 0x{:X}",
                     addr, instr, 8, "CP", "A", byte
                 );
@@ -673,36 +999,33 @@ impl Cpu {
             0xFF => {
                 self.rst(0x36);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:{}\t\tTime:{}\t\t{} 0x{:X}",
                     addr, instr, 32, "RST", 0x36
                 );
                 32
             }
 
-            _ => {
-                self.panic_dump(instr, false);
-
-                0
-            }
+            _ => return Err(self.unimplemented(addr, instr as u16)),
         };
 
-        if instr == 0xF3 {
-            self.mode_flags.change_intrpt_mode_on_next_instr = true;
-        } else if self.mode_flags.change_intrpt_mode_on_next_instr {
-            self.interrupts = !self.interrupts;
+        if instr == 0xFB {
+            self.mode_flags.enable_intrpt_after_next_instr = true;
+        } else if self.mode_flags.enable_intrpt_after_next_instr {
+            self.mode_flags.enable_intrpt_after_next_instr = false;
+            self.interrupts = true;
         }
 
-        cycles
+        Ok(cycles)
     }
 
-    fn exec_prefix_instr(&mut self, instr: u8) -> u8 {
+    fn exec_prefix_instr(&mut self, instr: u8) -> Result<u8, StopReason> {
         let addr = self.regs.PC - 2;
-        match instr {
+        let cycles = match instr {
             //RL C
             0x11 => {
                 self.regs.C = self.rl(self.regs.C);
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:CB {}\tTime:{}\t\t{} {}",
                     addr, instr, 8, "RL", "C"
                 );
@@ -713,7 +1036,7 @@ impl Cpu {
             0x7C => {
                 self.bit(self.regs.H, 7);
 
-                println!(
+                trace!(self;
                     "Addr:0x{:04X}\t\tOp:CB {:X}\tTime:{}\t\t{} {}, {}",
                     addr,
                     instr,
@@ -724,12 +1047,27 @@ impl Cpu {
                 );
                 8
             }
-            _ => {
-                self.panic_dump(instr, true);
+            _ => return Err(self.unimplemented(addr, 0xCB00 | instr as u16)),
+        };
 
-                0
-            }
+        Ok(cycles)
+    }
+
+    fn unimplemented(&mut self, pc: u16, opcode: u16) -> StopReason {
+        self.regs.PC = pc;
+        StopReason::UnimplementedOpcode { pc, opcode }
+    }
+
+    //Jumps to the vector of the highest priority interrupt both requested and enabled, with IME set
+    fn service_interrupt(&mut self) -> Option<u8> {
+        if !self.interrupts {
+            return None;
         }
+
+        let bit = self.mmu.acknowledge_interrupt()?;
+        self.interrupts = false;
+        self.rst(INTERRUPT_VECTORS + bit * 8);
+        Some(20)
     }
 
     fn stack_push_u16(&mut self, data: u16) {
@@ -855,7 +1193,7 @@ impl Cpu {
 
 //Debug
 impl Cpu {
-    fn dump(&self) {
+    pub fn dump(&self) {
         println!("Register dump:");
         println!("-AF: 0x{:04X}", self.regs.AF());
         println!("-BC: 0x{:04X}", self.regs.BC());
@@ -865,32 +1203,108 @@ impl Cpu {
         println!("-PC: 0x{:04X}", self.regs.PC);
         println!();
     }
+}
 
-    fn panic_dump(&self, instr: u8, prefix: bool) {
-        println!();
-        if prefix {
-            println!(
-                "Addr: 0x{:04X}\t\t Opcode CB {:02X} not implemented",
-                self.regs.PC - 1,
-                instr
-            );
-        } else {
-            println!(
-                "Addr: 0x{:04X}\t\t Opcode 0x{:02X} not implemented",
-                self.regs.PC - 1,
-                instr
-            );
+impl ModeChangeFlags {
+    fn new() -> Self {
+        ModeChangeFlags {
+            enable_intrpt_after_next_instr: false,
         }
+    }
+}
 
-        self.dump();
-        panic!();
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::WindowClosed => write!(f, "window closed"),
+            StopReason::FrameLimit => write!(f, "frame limit reached"),
+            StopReason::Condition => write!(f, "stop condition met"),
+            StopReason::Breakpoint => write!(f, "breakpoint"),
+            StopReason::Quit => write!(f, "quit"),
+            StopReason::UnimplementedOpcode { pc, opcode } if opcode >> 8 == 0xCB => write!(
+                f,
+                "opcode CB {:02X} not implemented at 0x{:04X}",
+                opcode & 0xFF,
+                pc
+            ),
+            StopReason::UnimplementedOpcode { pc, opcode } => {
+                write!(f, "opcode 0x{:02X} not implemented at 0x{:04X}", opcode, pc)
+            }
+        }
     }
 }
 
-impl ModeChangeFlags {
-    fn new() -> Self {
-        ModeChangeFlags {
-            change_intrpt_mode_on_next_instr: false,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOP: u8 = 0x00;
+    const DI: u8 = 0xF3;
+    const EI: u8 = 0xFB;
+    const RETI: u8 = 0xD9;
+
+    const VBLANK_VECTOR: u16 = 0x40;
+
+    //ROM only cartridge running program from 0x0100, VBlank requested and enabled
+    fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[VBLANK_VECTOR as usize] = RETI;
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom[0x14D] = rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.skip_boot();
+        cpu.mmu.write(0xFFFF, 0x01);
+        cpu.mmu.write(0xFF0F, 0x01);
+        cpu
+    }
+
+    fn step(cpu: &mut Cpu, count: usize) {
+        for _ in 0..count {
+            assert_eq!(cpu.step(), None);
         }
     }
+
+    #[test]
+    fn interrupts_disabled_at_start() {
+        let mut cpu = cpu_with_program(&[NOP, NOP, NOP]);
+        step(&mut cpu, 3);
+        assert_eq!(cpu.regs.PC, 0x103);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        let mut cpu = cpu_with_program(&[EI, NOP, NOP]);
+        step(&mut cpu, 2);
+        assert_eq!(cpu.regs.PC, 0x102);
+
+        step(&mut cpu, 1);
+        assert_eq!(cpu.regs.PC, VBLANK_VECTOR);
+        assert!(!cpu.interrupts);
+        assert_eq!(cpu.mmu.read(0xFF0F) & 0x01, 0);
+    }
+
+    #[test]
+    fn di_cancels_a_pending_ei() {
+        let mut cpu = cpu_with_program(&[EI, DI, NOP, NOP]);
+        step(&mut cpu, 4);
+        assert_eq!(cpu.regs.PC, 0x104);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_right_away() {
+        let mut cpu = cpu_with_program(&[EI, NOP, NOP, NOP]);
+        step(&mut cpu, 3);
+        assert_eq!(cpu.regs.PC, VBLANK_VECTOR);
+
+        cpu.mmu.write(0xFF0F, 0x01);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.regs.PC, 0x102);
+        assert!(cpu.interrupts);
+
+        step(&mut cpu, 1);
+        assert_eq!(cpu.regs.PC, VBLANK_VECTOR);
+    }
 }
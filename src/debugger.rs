@@ -0,0 +1,392 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crate::cpu::{Cpu, StopReason};
+use crate::regs::FlagsMasks;
+
+//PC, and the bank mapped there when given, 0x4000-0x7FFF for ROM banks
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Breakpoint {
+    pub bank: Option<u16>,
+    pub addr: u16,
+}
+
+//What the front end does once the prompt is left
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Resume {
+    Continue,
+    Quit,
+}
+
+//Command line debugger working on the Cpu alone, numbers are hexadecimal
+pub struct Debugger {
+    last_command: String,
+}
+
+const PROMPT: &str = "(gb) ";
+
+const HELP: &str = "\
+s, step [n]           run n instructions, 1 by default
+n, next               step over calls and RST
+f, finish             run until the current function returns
+c, continue           leave the debugger and run
+b, break [bank:]addr  add a breakpoint, without address list them
+d, delete <n>         delete breakpoint n
+r, regs               show registers and flags
+set <reg> <value>     set A to L, F, AF, BC, DE, HL, SP or PC
+flag <z|n|h|c> <0|1>  set or clear a flag
+x <addr> [length]     dump memory, 0x40 bytes by default
+t, trace              toggle logging each instruction run
+q, quit               stop the emulator
+An empty line repeats the last command";
+
+const DEFAULT_DUMP_LENGTH: u16 = 0x40;
+const DUMP_LINE_SIZE: u16 = 0x10;
+
+//Opcodes next steps over, with their length, and those finish waits for
+const CALL_OPCODES: [u8; 5] = [0xC4, 0xCC, 0xCD, 0xD4, 0xDC];
+const RST_OPCODES: [u8; 8] = [0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF];
+const RET_OPCODES: [u8; 6] = [0xC0, 0xC8, 0xC9, 0xD0, 0xD8, 0xD9];
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            last_command: String::new(),
+        }
+    }
+
+    //Prompt on stdin until continue or quit
+    pub fn enter(&mut self, cpu: &mut Cpu) -> Resume {
+        print_location(cpu);
+
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        loop {
+            print!("{}", PROMPT);
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) | Err(_) => return Resume::Quit,
+                Ok(_) => {}
+            }
+
+            let line = line.trim();
+            let command = if line.is_empty() {
+                self.last_command.clone()
+            } else {
+                self.last_command = line.to_string();
+                line.to_string()
+            };
+
+            match self.execute(cpu, &command) {
+                Ok(Some(resume)) => return resume,
+                Ok(None) => {}
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    fn execute(&mut self, cpu: &mut Cpu, command: &str) -> Result<Option<Resume>, String> {
+        let mut words = command.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let reason = match name {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => parse_hex(count)?,
+                    None => 1,
+                };
+                step(cpu, count)
+            }
+            "n" | "next" => next(cpu),
+            "f" | "finish" => finish(cpu),
+            "c" | "continue" => {
+                //Leave the breakpoint we are on, or it would stop again right away
+                if cpu.is_at_breakpoint() {
+                    if let Some(reason) = cpu.step() {
+                        return Ok(Some(stopped(reason)));
+                    }
+                }
+                return Ok(Some(Resume::Continue));
+            }
+            "b" | "break" => {
+                match args.first() {
+                    Some(breakpoint) => cpu.add_breakpoint(breakpoint.parse()?),
+                    None => print_breakpoints(cpu),
+                }
+                return Ok(None);
+            }
+            "d" | "delete" => {
+                let index = parse_hex(args.first().ok_or("delete expects a breakpoint number")?)?;
+                match cpu.remove_breakpoint(index as usize) {
+                    Some(breakpoint) => println!("Deleted breakpoint at {}", breakpoint),
+                    None => println!("No breakpoint {:X}", index),
+                }
+                return Ok(None);
+            }
+            "r" | "regs" => {
+                print_registers(cpu);
+                return Ok(None);
+            }
+            "set" => {
+                match args.as_slice() {
+                    [register, value] => set_register(cpu, register, parse_hex(value)?)?,
+                    _ => return Err("set expects a register and a value".to_string()),
+                }
+                print_registers(cpu);
+                return Ok(None);
+            }
+            "flag" => {
+                match args.as_slice() {
+                    [flag, value] => set_flag(cpu, flag, parse_hex(value)?)?,
+                    _ => return Err("flag expects a flag and 0 or 1".to_string()),
+                }
+                print_registers(cpu);
+                return Ok(None);
+            }
+            "x" => {
+                let addr = parse_hex(args.first().ok_or("x expects an address")?)?;
+                let length = match args.get(1) {
+                    Some(length) => parse_hex(length)?,
+                    None => DEFAULT_DUMP_LENGTH,
+                };
+                dump_memory(cpu, addr, length);
+                return Ok(None);
+            }
+            "t" | "trace" => {
+                cpu.set_trace(!cpu.is_tracing());
+                println!("Trace {}", if cpu.is_tracing() { "on" } else { "off" });
+                return Ok(None);
+            }
+            "q" | "quit" => return Ok(Some(Resume::Quit)),
+            "h" | "help" => {
+                println!("{}", HELP);
+                return Ok(None);
+            }
+            _ => return Err(format!("Unknown command {}, try help", name)),
+        };
+
+        Ok(reason.map(stopped))
+    }
+}
+
+//The emulator stopping while stepping ends the session
+fn stopped(reason: StopReason) -> Resume {
+    println!("Stopped: {}", reason);
+    Resume::Quit
+}
+
+fn step(cpu: &mut Cpu, count: u16) -> Option<StopReason> {
+    for _ in 0..count {
+        if let Some(reason) = cpu.step() {
+            return Some(reason);
+        }
+    }
+
+    print_location(cpu);
+    None
+}
+
+//Calls and RST run until they come back, unless a breakpoint is hit first
+fn next(cpu: &mut Cpu) -> Option<StopReason> {
+    let pc = cpu.regs().PC;
+    let opcode = cpu.peek(pc).unwrap_or(0);
+    let length = if CALL_OPCODES.contains(&opcode) {
+        3
+    } else if RST_OPCODES.contains(&opcode) {
+        1
+    } else {
+        return step(cpu, 1);
+    };
+
+    let return_addr = pc.wrapping_add(length);
+    let sp = cpu.regs().SP;
+    loop {
+        if let Some(reason) = cpu.step() {
+            return Some(reason);
+        }
+        let regs = cpu.regs();
+        if (regs.PC == return_addr && regs.SP >= sp) || cpu.is_at_breakpoint() {
+            break;
+        }
+    }
+
+    print_location(cpu);
+    None
+}
+
+//Run until a return pops the stack above where it is now
+fn finish(cpu: &mut Cpu) -> Option<StopReason> {
+    let sp = cpu.regs().SP;
+    loop {
+        let opcode = cpu.peek(cpu.regs().PC).unwrap_or(0);
+        if let Some(reason) = cpu.step() {
+            return Some(reason);
+        }
+        if (RET_OPCODES.contains(&opcode) && cpu.regs().SP > sp) || cpu.is_at_breakpoint() {
+            break;
+        }
+    }
+
+    print_location(cpu);
+    None
+}
+
+//PC with its bank and the bytes of the next instruction
+fn print_location(cpu: &Cpu) {
+    let pc = cpu.regs().PC;
+    let bytes: Vec<String> = (0..3)
+        .map(|i| match cpu.peek(pc.wrapping_add(i)) {
+            Some(byte) => format!("{:02X}", byte),
+            None => "--".to_string(),
+        })
+        .collect();
+
+    let location = Breakpoint {
+        bank: cpu.bank_at(pc),
+        addr: pc,
+    };
+    println!("{}: {}", location, bytes.join(" "));
+}
+
+fn print_registers(cpu: &Cpu) {
+    let regs = cpu.regs();
+    println!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}",
+        regs.AF(),
+        regs.BC(),
+        regs.DE(),
+        regs.HL(),
+        regs.SP,
+        regs.PC
+    );
+
+    let flag = |mask: FlagsMasks, name: char| if regs.get_flag(mask) { name } else { '-' };
+    println!(
+        "Flags: {}{}{}{}",
+        flag(FlagsMasks::Z, 'Z'),
+        flag(FlagsMasks::N, 'N'),
+        flag(FlagsMasks::H, 'H'),
+        flag(FlagsMasks::C, 'C')
+    );
+}
+
+fn print_breakpoints(cpu: &Cpu) {
+    if cpu.breakpoints().is_empty() {
+        println!("No breakpoints");
+    }
+    for (i, breakpoint) in cpu.breakpoints().iter().enumerate() {
+        println!("{:X}: {}", i, breakpoint);
+    }
+}
+
+fn set_register(cpu: &mut Cpu, register: &str, value: u16) -> Result<(), String> {
+    let regs = cpu.regs_mut();
+    let byte = value as u8;
+
+    match register.to_lowercase().as_str() {
+        "a" => regs.A = byte,
+        "b" => regs.B = byte,
+        "c" => regs.C = byte,
+        "d" => regs.D = byte,
+        "e" => regs.E = byte,
+        "h" => regs.H = byte,
+        "l" => regs.L = byte,
+        //The low bits of F always read 0
+        "f" => regs.F = byte & 0xF0,
+        "af" => {
+            regs.A = (value >> 8) as u8;
+            regs.F = byte & 0xF0;
+        }
+        "bc" => regs.set_BC(value),
+        "de" => regs.set_DE(value),
+        "hl" => regs.set_HL(value),
+        "sp" => regs.SP = value,
+        "pc" => regs.PC = value,
+        _ => return Err(format!("Unknown register {}", register)),
+    }
+
+    Ok(())
+}
+
+fn set_flag(cpu: &mut Cpu, flag: &str, value: u16) -> Result<(), String> {
+    let mask = match flag.to_lowercase().as_str() {
+        "z" => FlagsMasks::Z,
+        "n" => FlagsMasks::N,
+        "h" => FlagsMasks::H,
+        "c" => FlagsMasks::C,
+        _ => return Err(format!("Unknown flag {}", flag)),
+    };
+    match value {
+        0 | 1 => cpu.regs_mut().set_flag(mask, value == 1),
+        _ => return Err("Flags are 0 or 1".to_string()),
+    }
+
+    Ok(())
+}
+
+//16 bytes per line with their ASCII, -- where memory cannot be read
+fn dump_memory(cpu: &Cpu, addr: u16, length: u16) {
+    let end = addr as u32 + length as u32;
+    let mut line = addr as u32;
+    while line < end {
+        let bytes: Vec<Option<u8>> = (line..end.min(line + DUMP_LINE_SIZE as u32))
+            .map(|addr| cpu.peek(addr as u16))
+            .collect();
+
+        let hex: Vec<String> = bytes
+            .iter()
+            .map(|byte| match byte {
+                Some(byte) => format!("{:02X}", byte),
+                None => "--".to_string(),
+            })
+            .collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|byte| match byte {
+                Some(byte @ 0x20..=0x7E) => *byte as char,
+                _ => '.',
+            })
+            .collect();
+
+        println!("{:04X}: {:<47}  {}", line, hex.join(" "), ascii);
+        line += DUMP_LINE_SIZE as u32;
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("{} is not an hexadecimal number", value))
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((bank, addr)) => Ok(Breakpoint {
+                bank: Some(parse_hex(bank)?),
+                addr: parse_hex(addr)?,
+            }),
+            None => Ok(Breakpoint {
+                bank: None,
+                addr: parse_hex(s)?,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr),
+            None => write!(f, "{:04X}", self.addr),
+        }
+    }
+}
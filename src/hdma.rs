@@ -0,0 +1,80 @@
+//CGB VRAM DMA, HDMA1 to HDMA5 (0xFF51-0xFF55). The MMU copies the blocks
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    //Blocks left minus one, 0x7F once done
+    remaining: u8,
+    //HBlank DMA copying one block at the start of each HBlank
+    hblank: bool,
+}
+
+pub const BLOCK_SIZE: u16 = 0x10;
+
+//Only the last 4 bits of the addresses are fixed, the destination is always in VRAM
+const SOURCE_MASK: u16 = 0xFFF0;
+const DESTINATION_MASK: u16 = 0x1FF0;
+const VRAM_START: u16 = 0x8000;
+
+const HBLANK_MODE: u8 = 0b10000000;
+const LENGTH_MASK: u8 = 0x7F;
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            remaining: LENGTH_MASK,
+            hblank: false,
+        }
+    }
+
+    //HDMA1 to HDMA4 are write only, HDMA5 has bit 7 cleared while an HBlank DMA runs
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF55 if self.hblank => self.remaining,
+            0xFF55 => HBLANK_MODE | self.remaining,
+            _ => 0xFF,
+        }
+    }
+
+    //Returns the number of blocks a general purpose DMA copies right away
+    pub fn write(&mut self, addr: u16, data: u8) -> u8 {
+        match addr {
+            0xFF51 => self.source = (self.source & 0x00FF) | (data as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | data as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | (data as u16) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | data as u16,
+            //Clearing bit 7 during an HBlank DMA stops it, the length stays readable
+            0xFF55 if self.hblank && data & HBLANK_MODE == 0 => self.hblank = false,
+            0xFF55 => {
+                self.remaining = data & LENGTH_MASK;
+                if data & HBLANK_MODE > 0 {
+                    self.hblank = true;
+                } else {
+                    return self.remaining + 1;
+                }
+            }
+            _ => panic!("HDMA register write at {:X} not implemented", addr),
+        }
+
+        0
+    }
+
+    pub fn is_hblank_active(&self) -> bool {
+        self.hblank
+    }
+
+    //Source and VRAM destination of the next block, the addresses move on past it
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let source = self.source & SOURCE_MASK;
+        let destination = VRAM_START | (self.destination & DESTINATION_MASK);
+        self.source = source.wrapping_add(BLOCK_SIZE);
+        self.destination = (destination + BLOCK_SIZE) & DESTINATION_MASK;
+
+        self.remaining = self.remaining.wrapping_sub(1) & LENGTH_MASK;
+        if self.remaining == LENGTH_MASK {
+            self.hblank = false;
+        }
+        (source, destination)
+    }
+}
@@ -1,33 +1,69 @@
 extern crate minifb;
 
-use minifb::{Window, WindowOptions};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-const LCD_WIDTH: usize = 160;
-const LCD_HEIGHT: usize = 144;
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+pub const LCD_WIDTH: usize = 160;
+pub const LCD_HEIGHT: usize = 144;
+
+//Receives every frame produced by the PPU
+pub trait VideoSink {
+    fn update(&mut self, frame: &[u32]);
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+//The front end keeps a handle on the window to read its keys
+impl<T: VideoSink> VideoSink for Rc<RefCell<T>> {
+    fn update(&mut self, frame: &[u32]) {
+        self.borrow_mut().update(frame);
+    }
+
+    fn is_open(&self) -> bool {
+        self.borrow().is_open()
+    }
+}
+
+//Headless runs, frames are dropped
+pub struct NullSink;
+
+impl VideoSink for NullSink {
+    fn update(&mut self, _frame: &[u32]) {}
+}
 
 pub struct Lcd {
     window: Window,
 }
 
 impl Lcd {
-    pub fn new() -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         Lcd {
-            window: Window::new("GB-rs", LCD_WIDTH, LCD_HEIGHT, WindowOptions::default())
-                .unwrap_or_else(|e| {
+            window: Window::new("GB-rs", width, height, WindowOptions::default()).unwrap_or_else(
+                |e| {
                     panic!("{}", e);
-                }),
+                },
+            ),
         }
     }
 
-    pub fn update(&mut self, frame: &Vec<u32>) {
-        self.window.update_with_buffer(frame).unwrap();
-        // self.frame_print(frame);
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.window.is_key_down(key)
     }
 
-    pub fn frame_print(&mut self, frame: &Vec<u32>) {
-        for j in 0..LCD_HEIGHT {
-            for x in 0..LCD_WIDTH {
-                if frame[j as usize * LCD_WIDTH as usize + x as usize] == 0 {
+    //Only true on the frame the key goes down
+    pub fn is_key_pressed(&self, key: Key) -> bool {
+        self.window.is_key_pressed(key, KeyRepeat::No)
+    }
+
+    pub fn frame_print(&mut self, frame: &[u32]) {
+        let (width, height) = self.window.get_size();
+        for j in 0..height {
+            for x in 0..width {
+                if frame[j * width + x] == 0 {
                     print!(" ");
                 } else {
                     print!("1");
@@ -38,3 +74,14 @@ impl Lcd {
         }
     }
 }
+
+impl VideoSink for Lcd {
+    fn update(&mut self, frame: &[u32]) {
+        self.window.update_with_buffer(frame).unwrap();
+        // self.frame_print(frame);
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+}
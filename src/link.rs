@@ -0,0 +1,227 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+//Both ends run in lockstep: every QUANTUM_CYCLES they swap what happened on their side
+//and wait for the other, so a byte always lands at the same emulated time on both
+pub const QUANTUM_CYCLES: u32 = 4096;
+
+const HANDSHAKE: &[u8; 5] = b"GBLK\x01";
+
+const HAS_TRANSFER: u8 = 0b01;
+const HAS_REPLY: u8 = 0b10;
+
+//What one end did during a quantum
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Message {
+    //Byte sent with the internal clock, the other end answers in the next quantum
+    pub transfer: Option<u8>,
+    //Answer to the transfer of the other end during the previous quantum
+    pub reply: Option<u8>,
+}
+
+pub trait LinkTransport {
+    fn send(&mut self, message: Message) -> io::Result<()>;
+    fn receive(&mut self) -> io::Result<Message>;
+}
+
+pub type Transport = Box<dyn LinkTransport + Send>;
+
+//Other gb-rs process, over TCP or a Unix domain socket
+pub struct StreamTransport<S: Read + Write> {
+    stream: S,
+}
+
+//Other emulator in the same process, on another thread
+#[allow(dead_code)]
+pub struct ChannelTransport {
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+}
+
+impl<S: Read + Write> StreamTransport<S> {
+    fn new(mut stream: S) -> io::Result<Self> {
+        stream.write_all(HANDSHAKE)?;
+        let mut handshake = [0; 5];
+        stream.read_exact(&mut handshake)?;
+        if &handshake != HANDSHAKE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other end is not a gb-rs link cable",
+            ));
+        }
+
+        Ok(StreamTransport { stream })
+    }
+}
+
+impl<S: Read + Write> LinkTransport for StreamTransport<S> {
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        let flags =
+            message.transfer.map_or(0, |_| HAS_TRANSFER) | message.reply.map_or(0, |_| HAS_REPLY);
+        let data = [
+            flags,
+            message.transfer.unwrap_or(0),
+            message.reply.unwrap_or(0),
+        ];
+        self.stream.write_all(&data)?;
+        self.stream.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        let mut data = [0; 3];
+        self.stream.read_exact(&mut data)?;
+        Ok(Message {
+            transfer: if data[0] & HAS_TRANSFER > 0 {
+                Some(data[1])
+            } else {
+                None
+            },
+            reply: if data[0] & HAS_REPLY > 0 {
+                Some(data[2])
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl LinkTransport for ChannelTransport {
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "other end dropped"))
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        self.receiver
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "other end dropped"))
+    }
+}
+
+//Both ends of a cable for two emulators in one process, each must run on its own thread
+#[allow(dead_code)]
+pub fn pair() -> (Transport, Transport) {
+    let (first_sender, second_receiver) = channel();
+    let (second_sender, first_receiver) = channel();
+    (
+        Box::new(ChannelTransport {
+            sender: first_sender,
+            receiver: first_receiver,
+        }),
+        Box::new(ChannelTransport {
+            sender: second_sender,
+            receiver: second_receiver,
+        }),
+    )
+}
+
+//"host:port" is TCP, anything else the path of a Unix domain socket
+pub fn listen(address: &str) -> io::Result<Transport> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        return Ok(Box::new(StreamTransport::new(stream)?));
+    }
+
+    #[cfg(unix)]
+    {
+        let listener = UnixListener::bind(address)?;
+        let accepted = listener.accept();
+        std::fs::remove_file(address).ok();
+        Ok(Box::new(StreamTransport::new(accepted?.0)?))
+    }
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "expected host:port",
+    ))
+}
+
+pub fn connect(address: &str) -> io::Result<Transport> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        return Ok(Box::new(StreamTransport::new(stream)?));
+    }
+
+    #[cfg(unix)]
+    {
+        Ok(Box::new(StreamTransport::new(UnixStream::connect(
+            address,
+        )?)?))
+    }
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "expected host:port",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::Serial;
+    use std::thread;
+
+    //Enough for a transfer and its reply to cross the cable
+    const CYCLES: u32 = 4 * QUANTUM_CYCLES;
+
+    //Runs one end of the cable from a transfer start, returns SB and when the interrupt came
+    fn run_end(transport: Transport, data: u8, control: u8) -> thread::JoinHandle<(u8, Vec<u32>)> {
+        thread::spawn(move || {
+            let mut serial = Serial::new();
+            serial.connect(transport);
+            serial.write(0xFF01, data);
+            serial.write(0xFF02, control);
+
+            let mut interrupts = Vec::new();
+            for cycle in 0..CYCLES {
+                if serial.do_cycle(cycle as u16) {
+                    interrupts.push(cycle);
+                }
+            }
+            (serial.read(0xFF01), interrupts)
+        })
+    }
+
+    fn transfer(internal: u8, external: u8) -> ((u8, Vec<u32>), (u8, Vec<u32>)) {
+        let (first, second) = pair();
+        let master = run_end(first, internal, 0x81);
+        let slave = run_end(second, external, 0x80);
+        (master.join().unwrap(), slave.join().unwrap())
+    }
+
+    #[test]
+    fn bytes_cross_both_ways() {
+        let ((master_data, master_interrupts), (slave_data, slave_interrupts)) =
+            transfer(0x42, 0x99);
+        assert_eq!((master_data, slave_data), (0x99, 0x42));
+        //The slave gets the byte first, the master its reply a quantum later
+        assert_eq!(slave_interrupts, vec![QUANTUM_CYCLES - 1]);
+        assert_eq!(master_interrupts, vec![2 * QUANTUM_CYCLES - 1]);
+
+        let ((master_data, master_interrupts), (slave_data, slave_interrupts)) =
+            transfer(0x13, 0x37);
+        assert_eq!((master_data, slave_data), (0x37, 0x13));
+        assert_eq!(slave_interrupts.len(), 1);
+        assert_eq!(master_interrupts.len(), 1);
+    }
+
+    #[test]
+    fn nobody_waiting_on_the_external_clock() {
+        let (first, second) = pair();
+        let master = run_end(first, 0x42, 0x81);
+        let idle = run_end(second, 0x99, 0x00);
+
+        let (master_data, master_interrupts) = master.join().unwrap();
+        let (idle_data, idle_interrupts) = idle.join().unwrap();
+        assert_eq!(master_data, 0xFF);
+        assert_eq!(master_interrupts.len(), 1);
+        assert_eq!(idle_data, 0x99);
+        assert!(idle_interrupts.is_empty());
+    }
+}
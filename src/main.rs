@@ -1,32 +1,425 @@
+use std::cell::RefCell;
 use std::env;
+use std::fs;
 use std::path;
-use std::{thread, time};
+use std::process;
+use std::rc::Rc;
 
+mod apu;
+mod audio;
 mod cartridge;
+mod channel_view;
 mod cpu;
+mod debugger;
+mod hdma;
 mod lcd;
+mod link;
 mod mmu;
+mod model;
+mod pacer;
+mod palette;
+mod png;
 mod ppu;
+mod printer;
 mod regs;
+mod rom;
+mod serial;
+mod sgb;
+#[cfg(feature = "audio")]
+mod speaker;
+mod timer;
 
-use cpu::Cpu;
+use apu::Channel;
+use channel_view::ChannelView;
+use cpu::{Cpu, StopReason};
+use debugger::{Breakpoint, Debugger, Resume};
+use lcd::Lcd;
+use minifb::Key;
+use model::Model;
+use pacer::{Pacer, Speed};
+use palette::{ColorCorrection, DmgPalette};
+use printer::Printer;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("No file specified !");
+const USAGE: &str = "Usage: gb-rs [--patch <file>] [--boot-rom <file>] [--skip-boot] \
+                     [--model dmg0|dmg|mgb|sgb|cgb|agb] [--palette green|pocket|light|<colors>] \
+                     [--color-correction off|cgb|agb] [--headless] [--frames <n>] [--stop-at <pc>] \
+                     [--fast-forward <n>] [--slow-motion <n>] [--record <file.wav>] [--record-stems] \
+                     [--link-listen <address>] [--link-connect <address>] [--printer <directory>] \
+                     [--debug] [--break <[bank:]address>] <rom>";
+
+//Held down to change the emulation speed
+const FAST_FORWARD_KEY: Key = Key::Tab;
+const SLOW_MOTION_KEY: Key = Key::Backquote;
+
+//F1 to F4 mute a channel, with shift they solo it
+const CHANNEL_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+const CHANNEL_VIEW_KEY: Key = Key::F5;
+//Pauses into the debugger on the terminal
+const DEBUG_KEY: Key = Key::F12;
+
+struct Options {
+    rom: path::PathBuf,
+    patch: Option<path::PathBuf>,
+    boot_rom: Option<path::PathBuf>,
+    skip_boot: bool,
+    model: Option<Model>,
+    palette: DmgPalette,
+    color_correction: ColorCorrection,
+    headless: bool,
+    frames: Option<u64>,
+    stop_at: Option<u16>,
+    fast_forward: u32,
+    slow_motion: u32,
+    record: Option<path::PathBuf>,
+    record_stems: bool,
+    link: Option<LinkOption>,
+    printer: Option<path::PathBuf>,
+    debug: bool,
+    breakpoints: Vec<Breakpoint>,
+}
+
+//host:port for TCP, otherwise the path of a Unix domain socket
+enum LinkOption {
+    Listen(String),
+    Connect(String),
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
+    let mut patch = None;
+    let mut boot_rom = None;
+    let mut skip_boot = false;
+    let mut model = None;
+    let mut palette = DmgPalette::Green;
+    let mut color_correction = ColorCorrection::Off;
+    let mut headless = false;
+    let mut frames = None;
+    let mut stop_at = None;
+    let mut fast_forward = 0;
+    let mut slow_motion = 2;
+    let mut record = None;
+    let mut record_stems = false;
+    let mut link = None;
+    let mut printer = None;
+    let mut debug = false;
+    let mut breakpoints = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--patch" => patch = args.next().map(path::PathBuf::from),
+            "--boot-rom" => boot_rom = args.next().map(path::PathBuf::from),
+            "--skip-boot" => skip_boot = true,
+            "--model" => {
+                model = match args.next().unwrap_or_default().parse::<Model>() {
+                    Ok(model) => Some(model),
+                    Err(e) => usage_exit(&e),
+                }
+            }
+            "--palette" => {
+                palette = match args.next().unwrap_or_default().parse::<DmgPalette>() {
+                    Ok(palette) => palette,
+                    Err(e) => usage_exit(&e),
+                }
+            }
+            "--color-correction" => {
+                color_correction = match args.next().unwrap_or_default().parse() {
+                    Ok(correction) => correction,
+                    Err(e) => usage_exit(&e),
+                }
+            }
+            "--headless" => headless = true,
+            "--frames" => {
+                frames = match args.next().unwrap_or_default().parse() {
+                    Ok(frames) => Some(frames),
+                    Err(_) => usage_exit("--frames expects a number of frames"),
+                }
+            }
+            "--stop-at" => {
+                let pc = args.next().unwrap_or_default();
+                stop_at = match u16::from_str_radix(pc.trim_start_matches("0x"), 16) {
+                    Ok(pc) => Some(pc),
+                    Err(_) => usage_exit("--stop-at expects an hexadecimal address"),
+                }
+            }
+            "--fast-forward" => {
+                fast_forward = match args.next().unwrap_or_default().parse() {
+                    Ok(factor) => factor,
+                    Err(_) => usage_exit("--fast-forward expects a speed factor, 0 for uncapped"),
+                }
+            }
+            "--slow-motion" => {
+                slow_motion = match args.next().unwrap_or_default().parse() {
+                    Ok(factor) => factor,
+                    Err(_) => usage_exit("--slow-motion expects a slow down factor"),
+                }
+            }
+            "--record" => record = args.next().map(path::PathBuf::from),
+            "--record-stems" => record_stems = true,
+            "--link-listen" => link = args.next().map(LinkOption::Listen),
+            "--link-connect" => link = args.next().map(LinkOption::Connect),
+            "--printer" => printer = args.next().map(path::PathBuf::from),
+            "--debug" => debug = true,
+            "--break" => match args.next().unwrap_or_default().parse::<Breakpoint>() {
+                Ok(breakpoint) => breakpoints.push(breakpoint),
+                Err(e) => usage_exit(&e),
+            },
+            _ => rom = Some(path::PathBuf::from(arg)),
+        }
+    }
+
+    let options = match rom {
+        Some(rom) => Options {
+            rom,
+            patch,
+            boot_rom,
+            skip_boot,
+            model,
+            palette,
+            color_correction,
+            headless,
+            frames,
+            stop_at,
+            fast_forward,
+            slow_motion,
+            record,
+            record_stems,
+            link,
+            printer,
+            debug,
+            breakpoints,
+        },
+        None => usage_exit("No file specified !"),
+    };
+    if options.record_stems && options.record.is_none() {
+        usage_exit("--record-stems needs --record");
+    }
+    if options.link.is_some() && options.printer.is_some() {
+        usage_exit("The link cable and the printer share the serial port");
+    }
+
+    options
+}
+
+fn usage_exit(message: &str) -> ! {
+    println!("{}", message);
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+fn load_boot_rom(path: &path::Path) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    match data.len() {
+        mmu::BOOT_ROM_SIZE | mmu::CGB_BOOT_ROM_SIZE => Ok(data),
+        size => Err(format!(
+            "{} bytes, expected {} (DMG, MGB, SGB) or {} (CGB)",
+            size,
+            mmu::BOOT_ROM_SIZE,
+            mmu::CGB_BOOT_ROM_SIZE
+        )),
     }
+}
 
-    let path = path::Path::new(&args[1]);
+fn main() {
+    let options = parse_args();
+
+    let path = options.rom.as_path();
     if !path.is_file() {
         panic!("Path does not exist or is not a file !");
     }
 
-    let mut cpu = Cpu::new(path);
-    cpu.run();
+    let rom = rom::load(path).and_then(|rom| rom::patch(rom, path, options.patch.as_deref()));
+    let mut cpu = match rom.and_then(|rom| Cpu::new(&rom)) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            println!("Unable to load {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    if let Some(boot_rom_path) = options.boot_rom.as_ref() {
+        match load_boot_rom(boot_rom_path) {
+            Ok(data) => cpu.set_boot_rom(data),
+            Err(e) => {
+                println!("Unable to load boot ROM {}: {}", boot_rom_path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(model) = options.model {
+        cpu.set_model(model);
+    }
+    if options.skip_boot {
+        cpu.skip_boot();
+    }
+    cpu.set_dmg_palette(options.palette);
+    cpu.set_color_correction(options.color_correction);
+    if let Err(e) = cpu.attach_save_file(path) {
+        println!("Unable to load save file: {}", e);
+    }
+    cpu.set_rumble_callback(Box::new(|on| {
+        println!("Rumble {}", if on { "on" } else { "off" });
+    }));
+    cpu.set_frame_limit(options.frames);
+    if let Some(pc) = options.stop_at {
+        cpu.set_stop_condition(Some(Box::new(move |regs| regs.PC == pc)));
+    }
+    for breakpoint in options.breakpoints.iter() {
+        cpu.add_breakpoint(*breakpoint);
+    }
+
+    if let Some(record_path) = options.record.as_ref() {
+        if let Err(e) = cpu.start_recording(record_path, options.record_stems) {
+            println!("Unable to record to {}: {}", record_path.display(), e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(link_option) = options.link.as_ref() {
+        let transport = match link_option {
+            LinkOption::Listen(address) => {
+                println!("Waiting for the other Game Boy on {}", address);
+                link::listen(address)
+            }
+            LinkOption::Connect(address) => link::connect(address),
+        };
+        match transport {
+            Ok(transport) => cpu.connect_link(transport),
+            Err(e) => {
+                println!("Unable to connect the link cable: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(directory) = options.printer.as_ref() {
+        if !directory.is_dir() {
+            println!("Printer directory {} does not exist", directory.display());
+            process::exit(1);
+        }
+        cpu.attach_serial_device(Box::new(Printer::new(directory.clone())));
+    }
+
+    println!(
+        "Running as {}{}",
+        cpu.model(),
+        if cpu.model().is_cgb() && !cpu.is_cgb_mode() {
+            " in DMG compatibility mode"
+        } else {
+            ""
+        }
+    );
+
+    let mut debugger = Debugger::new();
+    let reason = if options.debug && debugger.enter(&mut cpu) == Resume::Quit {
+        StopReason::Quit
+    } else if options.headless {
+        run_headless(&mut cpu, &mut debugger)
+    } else {
+        run_windowed(&mut cpu, &mut debugger, &options)
+    };
+    println!();
+    println!("Stopped: {}", reason);
+    let serial_output = cpu.take_serial_output();
+    if !serial_output.is_empty() {
+        println!("Serial output: {}", String::from_utf8_lossy(&serial_output));
+    }
+    cpu.dump();
+
+    if let Err(e) = cpu.stop_recording() {
+        println!("Unable to finish recording: {}", e);
+    }
+    if let Err(e) = cpu.save() {
+        println!("Unable to write save file: {}", e);
+    }
+}
+
+//Breakpoints open the debugger, the run goes on once it is left
+fn run_headless(cpu: &mut Cpu, debugger: &mut Debugger) -> StopReason {
+    loop {
+        let reason = cpu.run();
+        if reason != StopReason::Breakpoint {
+            return reason;
+        }
+        if debugger.enter(cpu) == Resume::Quit {
+            return StopReason::Quit;
+        }
+    }
+}
+
+//Real time loop, one paced frame at a time
+fn run_windowed(cpu: &mut Cpu, debugger: &mut Debugger, options: &Options) -> StopReason {
+    let (width, height) = cpu.screen_size();
+    let lcd = Rc::new(RefCell::new(Lcd::new(width, height)));
+    cpu.set_video_sink(Box::new(lcd.clone()));
+
+    let mut pacer = Pacer::new();
+    #[cfg(feature = "audio")]
+    match speaker::Speaker::new() {
+        Ok(speaker) => {
+            pacer.set_audio_level(Some(Box::new(speaker.level_monitor())));
+            cpu.set_audio_sink(Box::new(speaker));
+        }
+        Err(e) => println!("Audio disabled: {}", e),
+    }
 
-    println!("------------Thanks for trying this prototype------------");
+    let mut channel_view: Option<ChannelView> = None;
+    loop {
+        if let Some(reason) = cpu.run_frame() {
+            if reason != StopReason::Breakpoint {
+                return reason;
+            }
+            if debugger.enter(cpu) == Resume::Quit {
+                return StopReason::Quit;
+            }
+        }
+        if lcd.borrow().is_key_pressed(DEBUG_KEY) && debugger.enter(cpu) == Resume::Quit {
+            return StopReason::Quit;
+        }
 
-    let wait = time::Duration::from_millis(10000);
-    thread::sleep(wait);
+        handle_channel_keys(cpu, &lcd.borrow());
+        if lcd.borrow().is_key_pressed(CHANNEL_VIEW_KEY) {
+            channel_view = match channel_view {
+                Some(_) => None,
+                None => Some(ChannelView::new()),
+            };
+        }
+        if let Some(view) = channel_view.as_mut() {
+            view.update(&cpu.channel_states());
+            if !view.is_open() {
+                channel_view = None;
+            }
+        }
+
+        let speed = {
+            let lcd = lcd.borrow();
+            if lcd.is_key_down(FAST_FORWARD_KEY) {
+                Speed::FastForward(options.fast_forward)
+            } else if lcd.is_key_down(SLOW_MOTION_KEY) {
+                Speed::SlowMotion(options.slow_motion)
+            } else {
+                Speed::Normal
+            }
+        };
+        pacer.set_speed(speed);
+        pacer.wait();
+    }
+}
+
+fn handle_channel_keys(cpu: &mut Cpu, lcd: &Lcd) {
+    let solo = lcd.is_key_down(Key::LeftShift) || lcd.is_key_down(Key::RightShift);
+    for (&key, &channel) in CHANNEL_KEYS.iter().zip(Channel::ALL.iter()) {
+        if !lcd.is_key_pressed(key) {
+            continue;
+        }
+
+        if solo {
+            let soloed = cpu.solo() != Some(channel);
+            cpu.set_solo(if soloed { Some(channel) } else { None });
+            println!("{:?} solo {}", channel, if soloed { "on" } else { "off" });
+        } else {
+            let muted = !cpu.is_channel_muted(channel);
+            cpu.set_channel_muted(channel, muted);
+            println!("{:?} {}", channel, if muted { "muted" } else { "unmuted" });
+        }
+    }
 }
@@ -1,32 +1,135 @@
+use std::io;
 use std::path;
 
+use crate::apu;
 use crate::cartridge;
-use crate::cartridge::Cartridge;
-use crate::ppu;
+use crate::cartridge::save::SaveFile;
+use crate::cartridge::{Cartridge, CartridgeError, RumbleCallback};
+use crate::hdma::{self, Hdma};
+use crate::lcd;
+use crate::model::{self, Model};
+use crate::ppu::{self, ColorMode, LCDModes};
+use crate::serial::Serial;
+use crate::sgb::{self, Sgb};
+use crate::timer::Timer;
 
 pub struct Mmu {
-    cartridge: Box<Cartridge>,
+    cartridge: Box<dyn Cartridge>,
+    save_file: Option<SaveFile>,
+    save_cycles: usize,
     pub ppu: ppu::Ppu,
+    pub apu: apu::Apu,
+    pub serial: Serial,
+    timer: Timer,
+    sgb: Option<Sgb>,
 
-    dmg: Vec<u8>,
+    model: Model,
+    //False for DMG games on CGB hardware, running in compatibility mode
+    cgb_mode: bool,
+    //KEY1, STOP switches speed once prepared
+    double_speed: bool,
+    prepare_speed_switch: bool,
+    skipped_cycle: bool,
+    //RP, there is never another console to receive light from
+    infrared: u8,
+    //0xFF72 to 0xFF75, without known use, 0xFF74 only in CGB mode
+    undocumented: [u8; 4],
+
+    //Upper byte of the last OAM DMA source
+    oam_dma_source: u8,
+    hdma: Hdma,
+    //The CPU waits while VRAM DMA copies
+    dma_stall: usize,
+
+    //Upper byte is the DIV register
+    div: u16,
+    //P1 lines selecting the directions (bit 4) or the buttons (bit 5), low when selected
+    joypad_select: u8,
+
+    //IF and IE
+    interrupt_flags: u8,
+    interrupt_enable: u8,
+
+    boot_rom: Vec<u8>,
+    //8 banks of 4 KiB, 0xD000-0xDFFF maps one of banks 1 to 7 through SVBK in CGB mode
     ram: Vec<u8>,
+    wram_bank: u8,
     hram: Vec<u8>,
 
-    dmg_on: bool,
+    boot_rom_on: bool,
 }
 
-const RAM_SIZE: usize = 0xDFFF - 0xC000 + 1;
+const RAM_BANK_SIZE: usize = 0x1000;
+const RAM_BANKS: usize = 8;
 const HRAM_SIZE: usize = 0xFFFE - 0xFF80 + 1;
 
+pub const BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+//Nintendo logo in the cartridge header, copied to VRAM by the boot ROM
+const LOGO_START: u16 = 0x104;
+const LOGO_SIZE: u16 = 48;
+const LOGO_TILES_ADDR: u16 = 0x8010;
+const REGISTERED_TILE_ADDR: u16 = 0x8190;
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+//Bits of IF and IE, by priority
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+const OAM_DMA_SIZE: u16 = 0xA0;
+
+//A 16 bytes block takes 8 M-cycles, twice as many CPU cycles in double speed
+const DMA_BLOCK_CYCLES: usize = 32;
+
+//Flush battery RAM every ~5 seconds of emulated time
+const SAVE_INTERVAL: usize = 5 * 4194304;
+
 impl Mmu {
-    pub fn new(path: &path::Path) -> Self {
-        Mmu {
-            cartridge: cartridge::new(path),
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        let cartridge = cartridge::new(rom)?;
+        let model = Model::detect(cartridge.read_rom(model::CGB_FLAG_ADDR));
+
+        Ok(Mmu {
+            cartridge,
+            save_file: None,
+            save_cycles: 0,
             ppu: ppu::Ppu::new(),
+            apu: apu::Apu::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            sgb: None,
+
+            model,
+            //CGB boot ROMs run in CGB mode and pick the mode of the game through KEY0
+            cgb_mode: model.is_cgb(),
+            double_speed: false,
+            prepare_speed_switch: false,
+            skipped_cycle: false,
+            infrared: 0,
+            undocumented: [0; 4],
+
+            oam_dma_source: 0,
+            hdma: Hdma::new(),
+            dma_stall: 0,
+
+            div: 0,
+            joypad_select: 0x30,
+
+            interrupt_flags: 0,
+            interrupt_enable: 0,
 
-            ram: vec![0; RAM_SIZE],
+            ram: vec![0; RAM_BANK_SIZE * RAM_BANKS],
+            wram_bank: 0,
             hram: vec![0; HRAM_SIZE],
-            dmg: vec![
+            boot_rom: vec![
                 0x31, 0xfe, 0xff, 0xaf, 0x21, 0xff, 0x9f, 0x32, 0xcb, 0x7c, 0x20, 0xfb, 0x21, 0x26,
                 0xff, 0xe, 0x11, 0x3e, 0x80, 0x32, 0xe2, 0xc, 0x3e, 0xf3, 0xe2, 0x32, 0x3e, 0x77,
                 0x77, 0x3e, 0xfc, 0xe0, 0x47, 0x11, 0x4, 0x1, 0x21, 0x10, 0x80, 0x1a, 0xcd, 0x95,
@@ -48,39 +151,340 @@ impl Mmu {
                 0x1, 0xe0, 0x50,
             ],
 
-            dmg_on: true,
+            boot_rom_on: true,
+        })
+    }
+
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) {
+        self.boot_rom = data;
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.set_cgb_mode(model.is_cgb());
+
+        self.sgb = if model == Model::Sgb {
+            let sgb_flag = self.cartridge.read_rom(sgb::SGB_FLAG_ADDR);
+            let old_licensee = self.cartridge.read_rom(sgb::OLD_LICENSEE_ADDR);
+            Some(Sgb::new(sgb::supports_sgb(sgb_flag, old_licensee)))
+        } else {
+            None
+        };
+        self.ppu.set_sgb_output(self.sgb.is_some());
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    //The SGB adds its border around the screen
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.sgb {
+            Some(_) => (sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT),
+            None => (lcd::LCD_WIDTH, lcd::LCD_HEIGHT),
+        }
+    }
+
+    //Bank mapped at an address, for bank:address breakpoints
+    pub fn bank_at(&self, addr: u16) -> Option<u16> {
+        match addr {
+            0x0000...0x3FFF => Some(0),
+            0x4000...0x7FFF => Some(self.cartridge.rom_bank()),
+            0x8000...0x9FFF => Some(self.ppu.vram_bank() as u16),
+            0xD000...0xDFFF => Some(self.wram_bank.max(1) as u16),
+            _ => None,
         }
     }
 
-    pub fn do_cycle(&mut self) {
+    //The I/O ports are left out, they are registers rather than memory
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000...0xFEFF | 0xFF80...0xFFFF => Some(self.read(addr)),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    //Banking is only available in CGB mode
+    fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.ppu
+            .set_color_mode(match (self.model.is_cgb(), cgb_mode) {
+                (true, true) => ColorMode::Cgb,
+                (true, false) => ColorMode::Compatibility,
+                _ => ColorMode::Dmg,
+            });
+        if !cgb_mode {
+            self.wram_bank = 0;
+            self.ppu.set_vram_bank(0);
+        }
+    }
+
+    //Offset in ram of an address in 0xC000-0xDFFF, bank 0 selects bank 1
+    fn ram_index(&self, addr: u16) -> usize {
+        let offset = addr as usize - 0xC000;
+        if offset < RAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank.max(1) as usize * RAM_BANK_SIZE + offset - RAM_BANK_SIZE
+        }
+    }
+
+    //DMG games run in compatibility mode on CGB hardware
+    fn select_mode_from_header(&mut self) {
+        let cgb_flag = self.cartridge.read_rom(model::CGB_FLAG_ADDR);
+        self.set_cgb_mode(self.model.is_cgb() && model::supports_cgb(cgb_flag));
+    }
+
+    //Returns true when STOP switches between normal and double speed
+    pub fn stop(&mut self) -> bool {
+        if !self.cgb_mode || !self.prepare_speed_switch {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.prepare_speed_switch = false;
+        true
+    }
+
+    //Unmap the boot ROM and leave the hardware as the boot ROM of the model would
+    pub fn skip_boot(&mut self) {
+        self.boot_rom_on = false;
+        self.select_mode_from_header();
+        let model = self.model;
+        self.div = model.div_after_boot();
+
+        let registers = [
+            //VBlank is still requested from the last boot ROM frame
+            (0xFF0F, 0xE1),
+            //Sound, NR14 without the trigger bit so the boot sound does not play again
+            (0xFF26, 0x80),
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF14, 0x3F),
+            (0xFF16, 0x3F),
+            (0xFF17, 0x00),
+            (0xFF19, 0xBF),
+            (0xFF1A, 0x7F),
+            (0xFF1B, 0xFF),
+            (0xFF1C, 0x9F),
+            (0xFF1E, 0xBF),
+            (0xFF20, 0xFF),
+            (0xFF21, 0x00),
+            (0xFF22, 0x00),
+            (0xFF23, 0xBF),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+            //PPU / LCD
+            (0xFF40, 0x91),
+            (0xFF42, 0x00),
+            (0xFF47, 0xFC),
+        ];
+        for (addr, data) in registers.iter() {
+            self.ioports_write(*addr, *data);
+        }
+
+        match model {
+            Model::Cgb | Model::Agb if !self.cgb_mode => self.ppu.load_compatibility_palettes(),
+            Model::Cgb | Model::Agb => {}
+            _ => self.load_logo(),
+        }
+    }
+
+    //Logo tiles scaled up 2x and the (R) tile, as drawn by the DMG boot ROM
+    fn load_logo(&mut self) {
+        for i in 0..LOGO_SIZE {
+            let byte = self.cartridge.read_rom(LOGO_START + i);
+            for (j, nibble) in [byte >> 4, byte & 0x0F].iter().enumerate() {
+                let row = (0..4).fold(0u8, |row, bit| {
+                    row | ((nibble >> bit & 1) * 0b11) << (bit * 2)
+                });
+
+                let addr = LOGO_TILES_ADDR + i * 8 + j as u16 * 4;
+                self.ppu.write(addr - 0x8000, row);
+                self.ppu.write(addr + 2 - 0x8000, row);
+            }
+        }
+
+        for (i, row) in REGISTERED_TILE.iter().enumerate() {
+            self.ppu
+                .write(REGISTERED_TILE_ADDR + i as u16 * 2 - 0x8000, *row);
+        }
+
+        self.ppu.write(0x9910 - 0x8000, 0x19);
+        for tile in 1..=12 {
+            self.ppu.write(0x9903 + tile - 0x8000, tile as u8);
+            self.ppu.write(0x9923 + tile - 0x8000, tile as u8 + 12);
+        }
+    }
+
+    //Returns false for the cycles skipped by the PPU and APU in double speed
+    pub fn do_cycle(&mut self) -> bool {
+        //DIV, and the serial port and timer clocked from it, follow the CPU speed
+        self.div = self.div.wrapping_add(1);
+        if self.serial.do_cycle(self.div) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        if self.timer.do_cycle(self.div) {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        self.dma_stall = self.dma_stall.saturating_sub(1);
+
+        if self.double_speed {
+            self.skipped_cycle = !self.skipped_cycle;
+            if self.skipped_cycle {
+                return false;
+            }
+        }
+
+        let mode = self.ppu.get_mode();
         self.ppu.do_cycle();
+        let new_mode = self.ppu.get_mode();
+        if mode != new_mode {
+            if new_mode == LCDModes::HBLANK && self.hdma.is_hblank_active() {
+                self.transfer_dma_blocks(1);
+            }
+            if new_mode == LCDModes::VBLANK {
+                self.request_interrupt(Interrupt::VBlank);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    let frame = sgb.end_frame(self.ppu.shades());
+                    self.ppu.present(frame);
+                }
+            }
+        }
+        //The frame sequencer moves to the next DIV bit to stay at 512 Hz
+        let apu_div = if self.double_speed {
+            self.div >> 1
+        } else {
+            self.div
+        };
+        self.apu.do_cycle(apu_div);
+        self.cartridge.do_cycle();
+
+        self.save_cycles += 1;
+        if self.save_cycles == SAVE_INTERVAL {
+            self.save_cycles = 0;
+            if let Err(e) = self.save() {
+                println!("Unable to write save file: {}", e);
+            }
+        }
+
+        true
+    }
+
+    pub fn is_dma_stalling(&self) -> bool {
+        self.dma_stall > 0
+    }
+
+    fn transfer_dma_blocks(&mut self, blocks: u8) {
+        for _ in 0..blocks {
+            let (source, destination) = self.hdma.next_block();
+            for i in 0..hdma::BLOCK_SIZE {
+                let data = self.dma_read(source.wrapping_add(i));
+                self.ppu.write(destination - 0x8000 + i, data);
+            }
+        }
+
+        let speed_factor = if self.double_speed { 2 } else { 1 };
+        self.dma_stall += blocks as usize * DMA_BLOCK_CYCLES * speed_factor;
+    }
+
+    //DMA only reaches the cartridge, VRAM and WRAM, sources from 0xE000 read WRAM like echo RAM
+    fn dma_read(&self, addr: u16) -> u8 {
+        match addr {
+            0xE000...0xFFFF => self.ram[self.ram_index(addr - 0x2000)],
+            _ => self.read(addr),
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flags |= 1 << interrupt as u8;
+    }
+
+    //Highest priority interrupt both requested and enabled, its request is cleared
+    pub fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        let pending = self.interrupt_flags & self.interrupt_enable & 0x1F;
+        if pending == 0 {
+            return None;
+        }
+
+        let bit = pending.trailing_zeros() as u8;
+        self.interrupt_flags &= !(1 << bit);
+        Some(bit)
+    }
+
+    //Battery RAM is loaded from and saved next to the ROM file
+    pub fn attach_save_file(&mut self, rom_path: &path::Path) -> io::Result<()> {
+        if !self.cartridge.has_battery() {
+            return Ok(());
+        }
+
+        let mut save_file = SaveFile::new(rom_path);
+        save_file.load(self.cartridge.as_mut())?;
+        self.save_file = Some(save_file);
+
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        match self.save_file.as_mut() {
+            Some(save_file) => save_file.flush(self.cartridge.as_ref()),
+            None => Ok(()),
+        }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x00FF if self.dmg_on => self.dmg[addr as usize], //DMG
-            0x0000...0x3FFF => self.cartridge.read_rom(addr),
+            0x0000...0x00FF if self.boot_rom_on => self.boot_rom[addr as usize],
+            //CGB boot ROMs continue after the cartridge header
+            0x0200...0x08FF if self.boot_rom_on && self.boot_rom.len() > 0x100 => {
+                self.boot_rom[addr as usize]
+            }
+            0x0000...0x7FFF => self.cartridge.read_rom(addr),
             0x8000...0x9FFF => self.ppu.read(addr - 0x8000),
+            0xA000...0xBFFF => self.cartridge.read_ram(addr - 0xA000),
+            0xC000...0xDFFF => self.ram[self.ram_index(addr)],
+            //Echo RAM mirrors 0xC000-0xDDFF
+            0xE000...0xFDFF => self.ram[self.ram_index(addr - 0x2000)],
+            0xFE00...0xFE9F => self.ppu.read_oam(addr - 0xFE00),
+            0xFEA0...0xFEFF => 0xFF,
             0xFF00...0xFF7F => self.ioports_read(addr),
             0xFF80...0xFFFE => self.hram[addr as usize - 0xFF80],
-
-            // 0xFF00...0xFF7E => self.ram[addr as usize - 0xFF00],
-            _ => panic!("Read at 0x{:X} not implemented", addr),
+            0xFFFF => self.interrupt_enable,
         }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
         match addr {
-            0xC000...0xDFFF => self.ram[addr as usize - 0xC000] = data,
+            //Cartridge controller registers
+            0x0000...0x7FFF => self.cartridge.write_rom(addr, data),
+            0xC000...0xDFFF => {
+                let index = self.ram_index(addr);
+                self.ram[index] = data;
+            }
+            0xE000...0xFDFF => {
+                let index = self.ram_index(addr - 0x2000);
+                self.ram[index] = data;
+            }
             //VRAM
             0x8000...0x9FFF => self.ppu.write(addr - 0x8000, data),
+            0xA000...0xBFFF => self.cartridge.write_ram(addr - 0xA000, data),
+            0xFE00...0xFE9F => self.ppu.write_oam(addr - 0xFE00, data),
+            0xFEA0...0xFEFF => {}
             0xFF00...0xFF7F => self.ioports_write(addr, data),
             0xFF80...0xFFFE => self.hram[addr as usize - 0xFF80] = data,
-
-            _ => panic!("Write at 0x{:X} not implemented", addr),
+            0xFFFF => self.interrupt_enable = data,
         }
     }
 
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.cartridge.set_rumble_callback(callback);
+    }
+
     pub fn write_u16(&mut self, addr: u16, data: u16) {
         self.write(addr, (data & 0x00FF) as u8);
         self.write(addr + 1, (data >> 8) as u8);
@@ -95,18 +499,204 @@ impl Mmu {
 
     fn ioports_read(&self, addr: u16) -> u8 {
         match addr {
-            0xFF40...0xFF4A => self.ppu.read_registers(addr),
-            _ => panic!("Read at 0x{:X} in I/O ports not implemented", addr),
+            //No button is pressed, the SGB answers with the joypad number when none is selected
+            0xFF00 => match self.sgb.as_ref() {
+                Some(sgb) if self.joypad_select == 0x30 => 0xC0 | 0x30 | sgb.joypad_id(),
+                _ => 0xC0 | self.joypad_select | 0x0F,
+            },
+            0xFF01...0xFF02 => self.serial.read(addr),
+            0xFF04 => (self.div >> 8) as u8,
+            0xFF05...0xFF07 => self.timer.read(addr),
+            0xFF0F => 0xE0 | self.interrupt_flags,
+            //Sound
+            0xFF10...0xFF3F => self.apu.read(addr),
+            0xFF40...0xFF45 | 0xFF47...0xFF4B | 0xFF68...0xFF6C => self.ppu.read_registers(addr),
+            0xFF46 => self.oam_dma_source,
+            0xFF4C => 0xFF,
+            0xFF4F if self.cgb_mode => 0xFE | self.ppu.vram_bank(),
+            0xFF4F => 0xFF,
+            0xFF51...0xFF55 if self.cgb_mode => self.hdma.read(addr),
+            0xFF51...0xFF55 => 0xFF,
+            0xFF4D if self.cgb_mode => {
+                (self.double_speed as u8) << 7 | 0x7E | self.prepare_speed_switch as u8
+            }
+            0xFF4D => 0xFF,
+            0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank,
+            0xFF70 => 0xFF,
+            //Bit 1 high, no light received
+            0xFF56 if self.cgb_mode => 0x3E | self.infrared,
+            0xFF56 => 0xFF,
+            0xFF72 | 0xFF73 if self.model.is_cgb() => self.undocumented[addr as usize - 0xFF72],
+            0xFF74 if self.cgb_mode => self.undocumented[2],
+            0xFF75 if self.model.is_cgb() => 0x8F | self.undocumented[3],
+            //Unused ports read as open bus
+            _ => 0xFF,
         }
     }
 
     fn ioports_write(&mut self, addr: u16, data: u8) {
         match addr {
-            0xFF26 | 0xFF11 | 0xFF12 | 0xFF13 | 0xFF14 | 0xFF24 | 0xFF25 => {} //TODO implement sound
+            //P1, the SGB also receives its command packets through it
+            0xFF00 => {
+                self.joypad_select = data & 0x30;
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(self.joypad_select);
+                }
+            }
+            0xFF01...0xFF02 => self.serial.write(addr, data),
+            //Any write resets the divider
+            0xFF04 => self.div = 0,
+            0xFF05...0xFF07 => self.timer.write(addr, data),
+            0xFF0F => self.interrupt_flags = data & 0x1F,
+            //Sound
+            0xFF10...0xFF3F => self.apu.write(addr, data),
             //PPU / LCD
-            0xFF40...0xFF4A => self.ppu.write_registers(addr, data),
-            0xFF50 => self.dmg_on = false,
-            _ => panic!("Write at 0x{:X} in I/O ports not implemented", addr),
+            0xFF40...0xFF45 | 0xFF47...0xFF4B | 0xFF68...0xFF6C => {
+                self.ppu.write_registers(addr, data)
+            }
+            //OAM DMA, copied at once
+            0xFF46 => {
+                self.oam_dma_source = data;
+                for i in 0..OAM_DMA_SIZE {
+                    let data = self.dma_read((self.oam_dma_source as u16) << 8 | i);
+                    self.ppu.write_oam(i, data);
+                }
+            }
+            //KEY0, the CGB boot ROM sets bit 2 for DMG games
+            0xFF4C if self.boot_rom_on && self.model.is_cgb() => {
+                self.set_cgb_mode(data & 0b00000100 == 0)
+            }
+            0xFF4D if self.cgb_mode => self.prepare_speed_switch = data & 0b00000001 > 0,
+            //VBK
+            0xFF4F if self.cgb_mode => self.ppu.set_vram_bank(data & 0x01),
+            //HDMA1 to HDMA5, an HBlank DMA started outside of the pixel output moves a block now
+            0xFF51...0xFF55 if self.cgb_mode => {
+                let blocks = self.hdma.write(addr, data);
+                if blocks > 0 {
+                    self.transfer_dma_blocks(blocks);
+                } else if addr == 0xFF55
+                    && self.hdma.is_hblank_active()
+                    && (!self.ppu.lcd_ison() || self.ppu.get_mode() == LCDModes::HBLANK)
+                {
+                    self.transfer_dma_blocks(1);
+                }
+            }
+            //SVBK
+            0xFF70 if self.cgb_mode => self.wram_bank = data & 0x07,
+            //The LED and read enable bits
+            0xFF56 if self.cgb_mode => self.infrared = data & 0xC1,
+            0xFF72 | 0xFF73 if self.model.is_cgb() => {
+                self.undocumented[addr as usize - 0xFF72] = data
+            }
+            0xFF74 if self.cgb_mode => self.undocumented[2] = data,
+            0xFF75 if self.model.is_cgb() => self.undocumented[3] = data & 0x70,
+            //A DMG boot ROM on CGB hardware never wrote KEY0
+            0xFF50 => {
+                self.boot_rom_on = false;
+                if self.boot_rom.len() != CGB_BOOT_ROM_SIZE {
+                    self.select_mode_from_header();
+                }
+            }
+            //Unused ports, and CGB registers outside of CGB mode, ignore writes
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booted(model: Model) -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+
+        let mut mmu = Mmu::new(&rom).unwrap();
+        mmu.set_model(model);
+        mmu.skip_boot();
+        mmu
+    }
+
+    fn cgb_mmu() -> Mmu {
+        let mut mmu = booted(Model::Cgb);
+        mmu.set_cgb_mode(true);
+        mmu
+    }
+
+    #[test]
+    fn div_after_boot() {
+        for (model, div) in [(Model::Dmg0, 0x18), (Model::Dmg, 0xAB), (Model::Mgb, 0xAB)].iter() {
+            assert_eq!(booted(*model).read(0xFF04), *div);
+        }
+    }
+
+    #[test]
+    fn unused_io_ports_read_0xff_and_ignore_writes() {
+        let mut mmu = cgb_mmu();
+        for addr in [0xFF03, 0xFF08, 0xFF0E, 0xFF4E, 0xFF57, 0xFF71, 0xFF7F].iter() {
+            mmu.write(*addr, 0x12);
+            assert_eq!(mmu.read(*addr), 0xFF);
+        }
+    }
+
+    #[test]
+    fn cgb_registers_without_emulated_hardware() {
+        let mut mmu = cgb_mmu();
+        mmu.write(0xFF56, 0xFF);
+        assert_eq!(mmu.read(0xFF56), 0xFF);
+        mmu.write(0xFF56, 0x00);
+        assert_eq!(mmu.read(0xFF56), 0x3E);
+
+        mmu.write(0xFF72, 0x12);
+        mmu.write(0xFF74, 0x34);
+        mmu.write(0xFF75, 0xFF);
+        assert_eq!(mmu.read(0xFF72), 0x12);
+        assert_eq!(mmu.read(0xFF74), 0x34);
+        assert_eq!(mmu.read(0xFF75), 0xFF);
+
+        mmu.set_cgb_mode(false);
+        assert_eq!(mmu.read(0xFF56), 0xFF);
+        assert_eq!(mmu.read(0xFF72), 0x12);
+        assert_eq!(mmu.read(0xFF74), 0xFF);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut mmu = cgb_mmu();
+        mmu.write(0xC123, 0x42);
+        assert_eq!(mmu.read(0xE123), 0x42);
+        mmu.write(0xFDFF, 0x99);
+        assert_eq!(mmu.read(0xDDFF), 0x99);
+    }
+
+    #[test]
+    fn general_purpose_dma_from_echo_ram() {
+        let mut mmu = cgb_mmu();
+        for i in 0..0x20 {
+            mmu.write(0xD000 + i, i as u8);
+        }
+
+        //2 blocks from 0xF000 to 0x8000
+        for (addr, data) in [
+            (0xFF51, 0xF0),
+            (0xFF52, 0x00),
+            (0xFF53, 0x00),
+            (0xFF54, 0x00),
+        ]
+        .iter()
+        {
+            mmu.write(*addr, *data);
+        }
+        mmu.write(0xFF55, 0x01);
+        assert_eq!(mmu.read(0xFF55), 0xFF);
+
+        while mmu.ppu.get_mode() != LCDModes::VBLANK {
+            mmu.do_cycle();
+        }
+        for i in 0..0x20 {
+            assert_eq!(mmu.read(0x8000 + i), i as u8);
         }
     }
 }
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+    Agb,
+}
+
+//Cartridge header byte 0x143: bit 7 for CGB support, 0xC0 for CGB only games
+pub const CGB_FLAG_ADDR: u16 = 0x143;
+const CGB_SUPPORT: u8 = 0x80;
+
+impl Model {
+    //Model a cartridge asks for when none is forced
+    pub fn detect(cgb_flag: u8) -> Model {
+        if supports_cgb(cgb_flag) {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    //Internal divider when the boot ROM hands over at 0x0100, DIV is its upper byte. Pan Docs
+    //only documents DIV for the DMG0, DMG and MGB, the SGB and CGB boot ROMs take a time that
+    //depends on the cartridge so they start from 0
+    pub fn div_after_boot(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1800,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Cgb | Model::Agb => 0x0000,
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        match self {
+            Model::Cgb | Model::Agb => true,
+            _ => false,
+        }
+    }
+}
+
+pub fn supports_cgb(cgb_flag: u8) -> bool {
+    cgb_flag & CGB_SUPPORT > 0
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!(
+                "unknown model {}, expected dmg0, dmg, mgb, sgb, cgb or agb",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        };
+
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,89 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cpu::CYCLES_PER_FRAME;
+
+const CPU_FREQUENCY: u64 = 4194304;
+
+//Give up catching up when this late, instead of running flat out for a while
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+//Buffer fill the audio sync aims for, and how hard it pulls the frame duration
+const AUDIO_TARGET_LEVEL: f64 = 0.5;
+const AUDIO_SYNC_STRENGTH: f64 = 0.05;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    Normal,
+    //0 runs uncapped
+    FastForward(u32),
+    SlowMotion(u32),
+}
+
+pub type AudioLevel = Box<dyn Fn() -> f64>;
+
+//Paces emulation by whole frames against the wall clock
+pub struct Pacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+    speed: Speed,
+    audio_level: Option<AudioLevel>,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Pacer {
+            frame_duration: Duration::from_nanos(
+                CYCLES_PER_FRAME as u64 * 1_000_000_000 / CPU_FREQUENCY,
+            ),
+            next_frame: Instant::now(),
+            speed: Speed::Normal,
+            audio_level: None,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        if self.speed != speed {
+            self.speed = speed;
+            self.next_frame = Instant::now();
+        }
+    }
+
+    //Buffer fill level between 0 and 1, used to stay in sync with the audio device
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    pub fn set_audio_level(&mut self, audio_level: Option<AudioLevel>) {
+        self.audio_level = audio_level;
+    }
+
+    //Sleep until the next frame is due, call once per emulated frame
+    pub fn wait(&mut self) {
+        let duration = match self.speed {
+            Speed::Normal => self.synced_duration(),
+            Speed::FastForward(0) => return,
+            Speed::FastForward(factor) => self.frame_duration / factor,
+            Speed::SlowMotion(factor) => self.frame_duration * factor.max(1),
+        };
+
+        //Deadlines advance by the frame duration, sleep errors do not accumulate
+        self.next_frame += duration;
+
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > MAX_LAG {
+            self.next_frame = now;
+        }
+    }
+
+    //A fuller audio buffer stretches frames slightly, an emptier one shortens them
+    fn synced_duration(&self) -> Duration {
+        match self.audio_level.as_ref() {
+            Some(audio_level) => {
+                let level = audio_level().max(0.0).min(1.0);
+                let correction = 1.0 + (level - AUDIO_TARGET_LEVEL) * AUDIO_SYNC_STRENGTH * 2.0;
+                Duration::from_nanos((self.frame_duration.as_nanos() as f64 * correction) as u64)
+            }
+            None => self.frame_duration,
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::str::FromStr;
+
+//Colors shown for the 4 DMG shades, from lightest to darkest
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DmgPalette {
+    Green,
+    Pocket,
+    Light,
+    Custom([u32; 4]),
+}
+
+//How the 15-bit colors of CGB mode end up on screen
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorCorrection {
+    //Channels scaled as is, saturated compared to the real screens
+    Off,
+    Cgb,
+    Agb,
+}
+
+const GREEN: [u32; 4] = [0x009BBC0F, 0x008BAC0F, 0x00306230, 0x000F380F];
+const POCKET: [u32; 4] = [0x00C4CFA1, 0x008B956D, 0x004D533C, 0x001F1F1F];
+//Backlit blue green screen of the Game Boy Light
+const LIGHT: [u32; 4] = [0x0000B581, 0x00009A71, 0x0000694A, 0x00004F3B];
+
+pub const COLORS: usize = 0x8000;
+
+impl DmgPalette {
+    pub fn colors(self) -> [u32; 4] {
+        match self {
+            DmgPalette::Green => GREEN,
+            DmgPalette::Pocket => POCKET,
+            DmgPalette::Light => LIGHT,
+            DmgPalette::Custom(colors) => colors,
+        }
+    }
+}
+
+impl FromStr for DmgPalette {
+    type Err = String;
+
+    //A name or 4 comma separated RGB hex colors, lightest first
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "green" => return Ok(DmgPalette::Green),
+            "pocket" => return Ok(DmgPalette::Pocket),
+            "light" => return Ok(DmgPalette::Light),
+            _ => {}
+        }
+
+        let error = || {
+            format!(
+                "unknown palette {}, expected green, pocket, light or 4 colors like ffffff,aaaaaa,555555,000000",
+                s
+            )
+        };
+        let colors = s
+            .split(',')
+            .map(|color| {
+                let color = color.trim().trim_start_matches('#');
+                match u32::from_str_radix(color, 16) {
+                    Ok(rgb) if color.len() == 6 => Ok(rgb),
+                    _ => Err(error()),
+                }
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        if colors.len() != 4 {
+            return Err(error());
+        }
+
+        let mut custom = [0; 4];
+        custom.copy_from_slice(&colors);
+        Ok(DmgPalette::Custom(custom))
+    }
+}
+
+impl FromStr for ColorCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ColorCorrection::Off),
+            "cgb" => Ok(ColorCorrection::Cgb),
+            "agb" => Ok(ColorCorrection::Agb),
+            _ => Err(format!(
+                "unknown color correction {}, expected off, cgb or agb",
+                s
+            )),
+        }
+    }
+}
+
+//RGB of every 15-bit color, computed once since the corrections are costly per pixel
+pub fn color_table(correction: ColorCorrection) -> Vec<u32> {
+    (0..COLORS as u16)
+        .map(|color| match correction {
+            ColorCorrection::Off => rgb555(color),
+            ColorCorrection::Cgb => cgb_correction(color),
+            ColorCorrection::Agb => agb_correction(color),
+        })
+        .collect()
+}
+
+//15-bit color without correction, the SGB shows them on a TV
+pub fn rgb555(color: u16) -> u32 {
+    let (r, g, b) = channels(color);
+    rgb(scale(r), scale(g), scale(b))
+}
+
+fn channels(color: u16) -> (u32, u32, u32) {
+    let channel = |shift: u16| ((color >> shift) & 0x1F) as u32;
+    (channel(0), channel(5), channel(10))
+}
+
+fn rgb(r: u32, g: u32, b: u32) -> u32 {
+    (r << 16) | (g << 8) | b
+}
+
+//5 to 8 bits, low bits repeated so 0x1F gives 0xFF
+fn scale(channel: u32) -> u32 {
+    (channel << 3) | (channel >> 2)
+}
+
+//The CGB screen bleeds colors into each other and never gets fully bright
+fn cgb_correction(color: u16) -> u32 {
+    let (r, g, b) = channels(color);
+    let mix = |red: u32, green: u32, blue: u32| (red * r + green * g + blue * b).min(960) >> 2;
+    rgb(mix(26, 4, 2), mix(0, 24, 8), mix(6, 4, 22))
+}
+
+//The AGB screen is darker, the channels go through its gamma before mixing.
+//Each output channel is a weighted mean so white stays neutral
+fn agb_correction(color: u16) -> u32 {
+    const LCD_GAMMA: f64 = 4.0;
+    const OUTPUT_GAMMA: f64 = 2.2;
+    const MAX_LEVEL: f64 = 232.0;
+
+    let (r, g, b) = channels(color);
+    let linear = |channel: u32| (channel as f64 / 31.0).powf(LCD_GAMMA);
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let mix = |weights: [f64; 3]| {
+        let level =
+            (weights[0] * r + weights[1] * g + weights[2] * b) / weights.iter().sum::<f64>();
+        (level.powf(1.0 / OUTPUT_GAMMA) * MAX_LEVEL).round() as u32
+    };
+
+    let red = mix([255.0, 50.0, 0.0]);
+    let green = mix([10.0, 230.0, 30.0]);
+    let blue = mix([50.0, 10.0, 220.0]);
+    rgb(red, green, blue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u16 = 0x7FFF;
+    const BLACK: u16 = 0x0000;
+
+    #[test]
+    fn palette_names() {
+        assert_eq!("green".parse(), Ok(DmgPalette::Green));
+        assert_eq!("Pocket".parse(), Ok(DmgPalette::Pocket));
+        assert_eq!("LIGHT".parse(), Ok(DmgPalette::Light));
+        assert!("grey".parse::<DmgPalette>().is_err());
+    }
+
+    #[test]
+    fn custom_palette() {
+        assert_eq!(
+            "ffffff, #AAAAAA,555555,#000000".parse(),
+            Ok(DmgPalette::Custom([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]))
+        );
+    }
+
+    #[test]
+    fn custom_palette_needs_4_colors_of_6_digits() {
+        assert!("ffffff,aaaaaa,555555".parse::<DmgPalette>().is_err());
+        assert!("ffffff,aaaaaa,555555,000000,000000"
+            .parse::<DmgPalette>()
+            .is_err());
+        assert!("fff,aaaaaa,555555,000000".parse::<DmgPalette>().is_err());
+        assert!("ffffff,aaaaaa,555555,0000000"
+            .parse::<DmgPalette>()
+            .is_err());
+        assert!("ffffff,aaaaaa,55555g,000000".parse::<DmgPalette>().is_err());
+        assert!("".parse::<DmgPalette>().is_err());
+    }
+
+    #[test]
+    fn color_correction_names() {
+        assert_eq!("off".parse(), Ok(ColorCorrection::Off));
+        assert_eq!("CGB".parse(), Ok(ColorCorrection::Cgb));
+        assert_eq!("agb".parse(), Ok(ColorCorrection::Agb));
+        assert!("gba".parse::<ColorCorrection>().is_err());
+    }
+
+    #[test]
+    fn correction_endpoints() {
+        assert_eq!(rgb555(WHITE), 0xFFFFFF);
+        assert_eq!(cgb_correction(WHITE), 0xF0F0F0);
+        assert_eq!(agb_correction(WHITE), 0xE8E8E8);
+
+        assert_eq!(rgb555(BLACK), 0x000000);
+        assert_eq!(cgb_correction(BLACK), 0x000000);
+        assert_eq!(agb_correction(BLACK), 0x000000);
+    }
+
+    #[test]
+    fn color_table_matches_the_correction() {
+        let table = color_table(ColorCorrection::Agb);
+        assert_eq!(table.len(), COLORS);
+        assert_eq!(table[WHITE as usize], agb_correction(WHITE));
+    }
+}
@@ -0,0 +1,51 @@
+extern crate crc32fast;
+extern crate flate2;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crc32fast::Hasher;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_GRAYSCALE: u8 = 0;
+const FILTER_NONE: u8 = 0;
+
+//8-bit grayscale image, one byte per pixel row by row
+pub fn write_grayscale(path: &Path, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    //Deflate compression, adaptive filtering and no interlacing
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_GRAYSCALE, 0, 0, 0]);
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks(width).take(height) {
+        encoder.write_all(&[FILTER_NONE])?;
+        encoder.write_all(row)?;
+    }
+    let data = encoder.finish()?;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&SIGNATURE)?;
+    write_chunk(&mut file, b"IHDR", &header)?;
+    write_chunk(&mut file, b"IDAT", &data)?;
+    write_chunk(&mut file, b"IEND", &[])?;
+    file.flush()
+}
+
+//Length, type, data and the CRC of type and data
+fn write_chunk(file: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut hasher = Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+
+    file.write_all(&(data.len() as u32).to_be_bytes())?;
+    file.write_all(kind)?;
+    file.write_all(data)?;
+    file.write_all(&hasher.finalize().to_be_bytes())
+}
@@ -1,21 +1,73 @@
-use crate::lcd::Lcd;
+use crate::lcd::{NullSink, VideoSink};
+use crate::palette::{self, ColorCorrection, DmgPalette};
 
 pub struct Ppu {
-    lcd: Lcd,
+    lcd: Box<dyn VideoSink>,
+    //Bank 1 follows bank 0, the CPU sees the one selected by VBK
     vram: Vec<u8>,
+    vram_bank: u8,
+    oam: Vec<u8>,
     frame: Vec<u32>,
+    //DMG shades of the frame, the SGB colorizes them and reads its VRAM transfers from them
+    shades: Vec<u8>,
+    sgb_output: bool,
+    color_mode: ColorMode,
+    //Displayed colors, the 4 DMG shades and every 15-bit CGB color
+    dmg_colors: [u32; 4],
+    color_table: Vec<u32>,
 
     cycles: usize,
 
     pub lcdc_control: u8,
     lcdc_status: u8,
     pub scy: u8,
-    // SCX: u8,
+    scx: u8,
     pub ly: u8,
-    // LYC: u8,
-    // WY: u8,
-    // WX: u8,
+    lyc: u8,
+    wy: u8,
+    wx: u8,
     pub bg_colorpalette: u8,
+    obj_palette0: u8,
+    obj_palette1: u8,
+
+    //CGB palettes, 8 of 4 colors in 15-bit BGR little endian, BCPS/OCPS select the byte
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    bg_palette_index: u8,
+    obj_palette_index: u8,
+    //OPRI, sprites by OAM index in CGB mode unless set to order them by X like the DMG
+    obj_priority: u8,
+}
+
+//How colors are produced: DMG shades, CGB palettes, or DMG games colorized on CGB hardware
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorMode {
+    Dmg,
+    Cgb,
+    Compatibility,
+}
+
+//BG map attributes in VRAM bank 1
+struct BgAttributes {
+    palette: u8,
+    bank: u8,
+    x_flip: bool,
+    y_flip: bool,
+    priority: bool,
+}
+
+struct Sprite {
+    top: i16,
+    left: i16,
+    tile: u8,
+    attributes: u8,
+}
+
+//A BG pixel before its palette is applied
+struct BgPixel {
+    color: u8,
+    palette: u8,
+    priority: bool,
 }
 
 #[derive(PartialEq)]
@@ -26,11 +78,6 @@ pub enum LCDModes {
     VBLANK = 1,
 }
 
-enum Colors {
-    BLACK = 0x00000000,
-    WHITE = 0x00FFFFFF,
-}
-
 const VIEWPORT_SIZE_X: u8 = 160;
 const VIEWPORT_SIZE_Y: u8 = 144;
 
@@ -38,9 +85,37 @@ const TILE_SIZE: u8 = 0x10;
 const BG_LINE_SIZE: u16 = 0x20;
 
 const VRAM_SIZE: usize = 0x9FFF - 0x8000 + 1;
+const VRAM_BANKS: usize = 2;
+const OAM_SIZE: usize = 0xA0;
+
+const SPRITES: usize = 40;
+const SPRITES_PER_LINE: usize = 10;
+const SPRITE_SIZE: usize = 4;
+
+const PALETTE_RAM_SIZE: usize = 0x40;
+const PALETTE_INDEX_MASK: u8 = 0x3F;
+const PALETTE_AUTO_INCREMENT: u8 = 0b10000000;
+
+const OPRI_COORDINATE: u8 = 0b00000001;
+
+//STAT interrupt selects, the coincidence flag and the mode are not writable
+const STAT_WRITE_MASK: u8 = 0b01111000;
+const STAT_COINCIDENCE: u8 = 0b00000100;
+
+const LCDC_BG_ENABLE: u8 = 0b00000001;
+const LCDC_OBJ_ENABLE: u8 = 0b00000010;
+const LCDC_OBJ_SIZE: u8 = 0b00000100;
+
+const ATTR_PRIORITY: u8 = 0b10000000;
+const ATTR_Y_FLIP: u8 = 0b01000000;
+const ATTR_X_FLIP: u8 = 0b00100000;
+const ATTR_DMG_PALETTE: u8 = 0b00010000;
+const ATTR_BANK: u8 = 0b00001000;
+const ATTR_CGB_PALETTE: u8 = 0b00000111;
 
 const HBLANK_TIME: usize = 207;
-const VBLANK_TIME: usize = 4560;
+const VBLANK_LINE_TIME: usize = 456;
+const LINES: u8 = 154;
 const OAM_TIME: usize = 83;
 const TRANSFER_TIME: usize = 175;
 
@@ -48,20 +123,84 @@ const TRANSFER_TIME: usize = 175;
 impl Ppu {
     pub fn new() -> Self {
         Ppu {
-            lcd: Lcd::new(),
-            vram: vec![0; VRAM_SIZE],
+            lcd: Box::new(NullSink),
+            vram: vec![0; VRAM_SIZE * VRAM_BANKS],
+            vram_bank: 0,
+            oam: vec![0; OAM_SIZE],
             frame: vec![0; VIEWPORT_SIZE_Y as usize * VIEWPORT_SIZE_X as usize],
+            shades: vec![0; VIEWPORT_SIZE_X as usize * VIEWPORT_SIZE_Y as usize],
+            sgb_output: false,
+            color_mode: ColorMode::Dmg,
+            dmg_colors: DmgPalette::Green.colors(),
+            color_table: palette::color_table(ColorCorrection::Off),
             cycles: 0,
 
             lcdc_control: 0,
             lcdc_status: 0 + LCDModes::OAM as u8,
             scy: 0,
+            scx: 0,
             ly: 0,
+            lyc: 0,
+            wy: 0,
+            wx: 0,
 
             bg_colorpalette: 0,
+            obj_palette0: 0,
+            obj_palette1: 0,
+
+            bg_palette_ram: [0; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0; PALETTE_RAM_SIZE],
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+            obj_priority: 0,
+        }
+    }
+
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.color_mode = color_mode;
+    }
+
+    //Frames go to the SGB instead of the video sink
+    pub fn set_sgb_output(&mut self, sgb_output: bool) {
+        self.sgb_output = sgb_output;
+    }
+
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    pub fn present(&mut self, frame: &[u32]) {
+        self.lcd.update(frame);
+    }
+
+    pub fn set_dmg_palette(&mut self, dmg_palette: DmgPalette) {
+        self.dmg_colors = dmg_palette.colors();
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_table = palette::color_table(correction);
+    }
+
+    //Colors the CGB boot ROM gives DMG games it does not recognize
+    pub fn load_compatibility_palettes(&mut self) {
+        let bg = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+        let obj = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+        for i in 0..4 {
+            self.bg_palette_ram[i * 2..i * 2 + 2].copy_from_slice(&u16::to_le_bytes(bg[i]));
+            self.obj_palette_ram[i * 2..i * 2 + 2].copy_from_slice(&u16::to_le_bytes(obj[i]));
+            self.obj_palette_ram[8 + i * 2..8 + i * 2 + 2]
+                .copy_from_slice(&u16::to_le_bytes(obj[i]));
         }
     }
 
+    pub fn set_video_sink(&mut self, sink: Box<dyn VideoSink>) {
+        self.lcd = sink;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.lcd.is_open()
+    }
+
     pub fn do_cycle(&mut self) {
         if !self.lcd_ison() {
             return;
@@ -86,11 +225,17 @@ impl Ppu {
         HBLANK_TIME
     }
 
+    //LY keeps counting through the 10 VBlank lines
     fn do_vblank(&mut self) -> usize {
-        self.lcd.update(&self.frame);
-        self.set_mode(LCDModes::OAM);
-        self.ly = 0;
-        VBLANK_TIME
+        if self.ly == VIEWPORT_SIZE_Y && !self.sgb_output {
+            self.lcd.update(&self.frame);
+        }
+        self.ly += 1;
+        if self.ly == LINES {
+            self.set_mode(LCDModes::OAM);
+            self.ly = 0;
+        }
+        VBLANK_LINE_TIME
     }
 
     fn do_oam(&mut self) -> usize {
@@ -108,19 +253,60 @@ impl Ppu {
         TRANSFER_TIME
     }
 
+    pub fn vram_bank(&self) -> u8 {
+        self.vram_bank
+    }
+
+    pub fn set_vram_bank(&mut self, bank: u8) {
+        self.vram_bank = bank;
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         if self.get_mode() == LCDModes::VBLANK {
-            self.vram[addr as usize]
+            self.vram[self.vram_bank as usize * VRAM_SIZE + addr as usize]
         } else {
             0xFF
         }
     }
 
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        self.oam[addr as usize]
+    }
+
+    pub fn write_oam(&mut self, addr: u16, data: u8) {
+        self.oam[addr as usize] = data;
+    }
+
     pub fn read_registers(&self, addr: u16) -> u8 {
         match addr {
+            0xFF40 => self.lcdc_control,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc {
+                    STAT_COINCIDENCE
+                } else {
+                    0
+                };
+                0x80 | self.lcdc_status | coincidence
+            }
             0xFF42 => self.scy,
+            0xFF43 => self.scx,
             0xFF44 => self.ly,
-            _ => panic!("Ppu register read at {:X} not implemented"),
+            0xFF45 => self.lyc,
+            0xFF47 => self.bg_colorpalette,
+            0xFF48 => self.obj_palette0,
+            0xFF49 => self.obj_palette1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            //Palettes are only reachable in CGB mode and outside of pixel transfer
+            0xFF68 | 0xFF6A if self.color_mode != ColorMode::Cgb => 0xFF,
+            0xFF68 => 0x40 | self.bg_palette_index,
+            0xFF6A => 0x40 | self.obj_palette_index,
+            0xFF69 | 0xFF6B if !self.palettes_accessible() => 0xFF,
+            0xFF69 => self.bg_palette_ram[(self.bg_palette_index & PALETTE_INDEX_MASK) as usize],
+            0xFF6B => self.obj_palette_ram[(self.obj_palette_index & PALETTE_INDEX_MASK) as usize],
+            0xFF6C if self.color_mode == ColorMode::Cgb => 0xFE | self.obj_priority,
+            0xFF6C => 0xFF,
+            _ => panic!("Ppu register read at 0x{:X} not implemented", addr),
         }
     }
 
@@ -128,16 +314,50 @@ impl Ppu {
         // if self.get_mode() == LCDModes::VBLANK {
         match addr {
             0xFF40 => self.lcdc_control = data,
+            0xFF41 => {
+                self.lcdc_status = (self.lcdc_status & !STAT_WRITE_MASK) | (data & STAT_WRITE_MASK)
+            }
             0xFF42 => self.scy = data,
+            0xFF43 => self.scx = data,
             0xFF44 => self.ly = data,
+            0xFF45 => self.lyc = data,
             0xFF47 => self.bg_colorpalette = data,
-            _ => panic!("Ppu register write at {:X} not implemented"),
+            0xFF48 => self.obj_palette0 = data,
+            0xFF49 => self.obj_palette1 = data,
+            0xFF4A => self.wy = data,
+            0xFF4B => self.wx = data,
+            0xFF6C if self.color_mode == ColorMode::Cgb => {
+                self.obj_priority = data & OPRI_COORDINATE
+            }
+            0xFF6C => {}
+            0xFF68 | 0xFF6A if self.color_mode != ColorMode::Cgb => {}
+            0xFF68 => self.bg_palette_index = data & (PALETTE_AUTO_INCREMENT | PALETTE_INDEX_MASK),
+            0xFF6A => self.obj_palette_index = data & (PALETTE_AUTO_INCREMENT | PALETTE_INDEX_MASK),
+            0xFF69 => {
+                if self.palettes_accessible() {
+                    let index = (self.bg_palette_index & PALETTE_INDEX_MASK) as usize;
+                    self.bg_palette_ram[index] = data;
+                }
+                if self.color_mode == ColorMode::Cgb {
+                    self.bg_palette_index = increment_palette_index(self.bg_palette_index);
+                }
+            }
+            0xFF6B => {
+                if self.palettes_accessible() {
+                    let index = (self.obj_palette_index & PALETTE_INDEX_MASK) as usize;
+                    self.obj_palette_ram[index] = data;
+                }
+                if self.color_mode == ColorMode::Cgb {
+                    self.obj_palette_index = increment_palette_index(self.obj_palette_index);
+                }
+            }
+            _ => panic!("Ppu register write at 0x{:X} not implemented", addr),
         }
         // }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
-        self.vram[addr as usize] = data;
+        self.vram[self.vram_bank as usize * VRAM_SIZE + addr as usize] = data;
     }
 
     pub fn get_mode(&self) -> LCDModes {
@@ -155,40 +375,224 @@ impl Ppu {
         self.lcdc_status += mode as u8;
     }
 
-    fn lcd_ison(&self) -> bool {
+    pub fn lcd_ison(&self) -> bool {
         self.lcdc_control & 0b10000000 > 0
     }
+
+    fn palettes_accessible(&self) -> bool {
+        self.color_mode == ColorMode::Cgb && self.get_mode() != LCDModes::TRANSFER
+    }
+}
+
+//Auto-increment wraps within the 64 bytes and only applies on writes
+fn increment_palette_index(index: u8) -> u8 {
+    if index & PALETTE_AUTO_INCREMENT == 0 {
+        return index;
+    }
+
+    PALETTE_AUTO_INCREMENT | (index.wrapping_add(1) & PALETTE_INDEX_MASK)
 }
 
 //Frame creation
 impl Ppu {
     fn line_gen(&mut self) {
         let line = self.ly;
-        let bg_y = (self.scy + line) / 8;
+        let sprites = self.line_sprites(line);
         for x in 0..VIEWPORT_SIZE_X {
-            let col = x as u8; //TODO add SCX
-            let bg_x = col / 8;
-
-            let tile_nb = self.bg_map_get_tile_number(bg_x, bg_y);
-            let pix = self.tile_get_pix(tile_nb, col % 8, line % 8);
-            self.frame[line as usize * VIEWPORT_SIZE_X as usize + x as usize] =
-                self.match_color(pix);
+            let bg = self.bg_pixel(x, line);
+            let sprite = self.sprite_pixel(&sprites, x, line);
+
+            let i = line as usize * VIEWPORT_SIZE_X as usize + x as usize;
+            self.frame[i] = match sprite {
+                Some((color, attributes)) if self.sprite_visible(&bg, attributes) => {
+                    self.shades[i] = dmg_shade(self.obj_dmg_palette(attributes), color);
+                    self.obj_color(color, attributes)
+                }
+                _ => {
+                    self.shades[i] = dmg_shade(self.bg_colorpalette, bg.color);
+                    self.bg_color(&bg)
+                }
+            };
         }
 
         self.ly += 1;
     }
 
-    fn tile_get_pix(&self, num: u8, x: u8, y: u8) -> u8 {
-        let addr = self.tile_addr(num);
-        let i = addr + y as u16 * 2;
-        let lsb = self.vram[i as usize];
-        let msb = self.vram[(i + 1) as usize];
+    fn bg_pixel(&self, x: u8, line: u8) -> BgPixel {
+        let col = x.wrapping_add(self.scx);
+        let row = line.wrapping_add(self.scy);
+        let bg_y = row / 8;
+        let bg_x = col / 8;
+
+        let tile_nb = self.bg_map_get_tile_number(bg_x, bg_y);
+        if self.color_mode != ColorMode::Cgb {
+            return BgPixel {
+                color: self.tile_get_pix(tile_nb, col % 8, row % 8),
+                palette: 0,
+                priority: false,
+            };
+        }
 
-        let msb_color = ((msb & (1 << 7 - x) > 0) as u8) << 1;
-        let lsb_color = (lsb & (1 << 7 - x) > 0) as u8;
-        let color_num = msb_color + lsb_color;
+        let attributes = self.bg_map_get_attributes(bg_x, bg_y);
+        let tile_x = if attributes.x_flip {
+            7 - col % 8
+        } else {
+            col % 8
+        };
+        let tile_y = if attributes.y_flip {
+            7 - row % 8
+        } else {
+            row % 8
+        };
+        BgPixel {
+            color: self.tile_bank_get_pix(attributes.bank, tile_nb, tile_x, tile_y),
+            palette: attributes.palette,
+            priority: attributes.priority,
+        }
+    }
+
+    //The first 10 sprites in OAM order on the line, sorted by drawing priority
+    fn line_sprites(&self, line: u8) -> Vec<Sprite> {
+        if self.lcdc_control & LCDC_OBJ_ENABLE == 0 {
+            return Vec::new();
+        }
 
-        color_num
+        let height = self.sprite_height();
+        let mut sprites: Vec<Sprite> = self
+            .oam
+            .chunks(SPRITE_SIZE)
+            .take(SPRITES)
+            .map(|entry| Sprite {
+                top: entry[0] as i16 - 16,
+                left: entry[1] as i16 - 8,
+                tile: entry[2],
+                attributes: entry[3],
+            })
+            .filter(|sprite| {
+                let line = line as i16;
+                line >= sprite.top && line < sprite.top + height
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+
+        //CGB mode keeps OAM order unless OPRI says otherwise, then the leftmost sprite wins and
+        //OAM order breaks ties
+        if self.color_mode != ColorMode::Cgb || self.obj_priority & OPRI_COORDINATE > 0 {
+            sprites.sort_by_key(|sprite| sprite.left);
+        }
+        sprites
+    }
+
+    //Color number and attributes of the highest priority opaque sprite pixel
+    fn sprite_pixel(&self, sprites: &[Sprite], x: u8, line: u8) -> Option<(u8, u8)> {
+        let height = self.sprite_height();
+        for sprite in sprites {
+            let col = x as i16 - sprite.left;
+            if !(0..8).contains(&col) {
+                continue;
+            }
+
+            let mut row = line as i16 - sprite.top;
+            if sprite.attributes & ATTR_Y_FLIP > 0 {
+                row = height - 1 - row;
+            }
+            let col = if sprite.attributes & ATTR_X_FLIP > 0 {
+                7 - col
+            } else {
+                col
+            };
+
+            let tile = if height == 16 {
+                (sprite.tile & 0xFE) + (row / 8) as u8
+            } else {
+                sprite.tile
+            };
+            let bank = if self.color_mode == ColorMode::Cgb && sprite.attributes & ATTR_BANK > 0 {
+                1
+            } else {
+                0
+            };
+
+            let color = self.tile_bank_get_pix(bank, tile, col as u8, (row % 8) as u8);
+            if color != 0 {
+                return Some((color, sprite.attributes));
+            }
+        }
+
+        None
+    }
+
+    //In CGB mode LCDC bit 0 off puts every sprite above the BG, otherwise BG colors 1 to 3
+    //cover sprites when either the BG attributes or the sprite asks for it
+    fn sprite_visible(&self, bg: &BgPixel, attributes: u8) -> bool {
+        if bg.color == 0 {
+            return true;
+        }
+
+        match self.color_mode {
+            ColorMode::Cgb if self.lcdc_control & LCDC_BG_ENABLE == 0 => true,
+            ColorMode::Cgb => !bg.priority && attributes & ATTR_PRIORITY == 0,
+            _ => attributes & ATTR_PRIORITY == 0,
+        }
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc_control & LCDC_OBJ_SIZE > 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn bg_color(&self, bg: &BgPixel) -> u32 {
+        match self.color_mode {
+            ColorMode::Dmg => self.match_color(dmg_shade(self.bg_colorpalette, bg.color)),
+            ColorMode::Cgb => self.palette_color(&self.bg_palette_ram, bg.palette, bg.color),
+            ColorMode::Compatibility => self.palette_color(
+                &self.bg_palette_ram,
+                0,
+                dmg_shade(self.bg_colorpalette, bg.color),
+            ),
+        }
+    }
+
+    fn obj_dmg_palette(&self, attributes: u8) -> u8 {
+        if attributes & ATTR_DMG_PALETTE > 0 {
+            self.obj_palette1
+        } else {
+            self.obj_palette0
+        }
+    }
+
+    fn obj_color(&self, color: u8, attributes: u8) -> u32 {
+        let dmg_palette = self.obj_dmg_palette(attributes);
+        let dmg_palette_number = (attributes & ATTR_DMG_PALETTE > 0) as u8;
+
+        match self.color_mode {
+            ColorMode::Dmg => self.match_color(dmg_shade(dmg_palette, color)),
+            ColorMode::Cgb => {
+                self.palette_color(&self.obj_palette_ram, attributes & ATTR_CGB_PALETTE, color)
+            }
+            ColorMode::Compatibility => self.palette_color(
+                &self.obj_palette_ram,
+                dmg_palette_number,
+                dmg_shade(dmg_palette, color),
+            ),
+        }
+    }
+
+    fn tile_bank_get_pix(&self, bank: u8, num: u8, x: u8, y: u8) -> u8 {
+        let i = bank as usize * VRAM_SIZE + self.tile_addr(num) as usize + y as usize * 2;
+        let lsb = self.vram[i];
+        let msb = self.vram[i + 1];
+
+        let msb_color = ((msb & (1 << (7 - x)) > 0) as u8) << 1;
+        let lsb_color = (lsb & (1 << (7 - x)) > 0) as u8;
+        msb_color + lsb_color
+    }
+
+    fn tile_get_pix(&self, num: u8, x: u8, y: u8) -> u8 {
+        self.tile_bank_get_pix(0, num, x, y)
     }
 
     fn tile_addr(&self, num: u8) -> u16 {
@@ -200,12 +604,33 @@ impl Ppu {
         self.vram[index as usize]
     }
 
-    fn match_color(&self, color: u8) -> u32 {
-        match color {
-            0 => Colors::BLACK as u32,
-            _ => Colors::WHITE as u32,
+    fn bg_map_get_attributes(&self, x: u8, y: u8) -> BgAttributes {
+        let index =
+            VRAM_SIZE + (0x9800 - 0x8000) + (y as usize * BG_LINE_SIZE as usize + x as usize);
+        let attributes = self.vram[index];
+        BgAttributes {
+            palette: attributes & ATTR_CGB_PALETTE,
+            bank: (attributes & ATTR_BANK > 0) as u8,
+            x_flip: attributes & ATTR_X_FLIP > 0,
+            y_flip: attributes & ATTR_Y_FLIP > 0,
+            priority: attributes & ATTR_PRIORITY > 0,
         }
     }
+
+    fn match_color(&self, shade: u8) -> u32 {
+        self.dmg_colors[shade as usize]
+    }
+
+    fn palette_color(&self, palette_ram: &[u8], palette: u8, color: u8) -> u32 {
+        let index = palette as usize * 8 + color as usize * 2;
+        let color = u16::from_le_bytes([palette_ram[index], palette_ram[index + 1]]);
+        self.color_table[color as usize & (palette::COLORS - 1)]
+    }
+}
+
+//Shade 0 to 3 a DMG palette register gives a color number
+fn dmg_shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
 }
 
 //Debug
@@ -215,7 +640,7 @@ impl Ppu {
         for j in 0..8 {
             for i in 0..8 {
                 let pix_color = self.tile_get_pix(num, i, j);
-                if pix_color as u32 == Colors::BLACK as u32 {
+                if pix_color == 0 {
                     print!(" ");
                 } else {
                     print!("1");
@@ -232,10 +657,12 @@ impl Ppu {
         for j in 0..VIEWPORT_SIZE_Y {
             self.line_gen();
             for x in 0..VIEWPORT_SIZE_X {
-                if self.frame[j as usize * VIEWPORT_SIZE_X as usize + x as usize] == 0 {
-                    print!("1");
-                } else {
+                if self.frame[j as usize * VIEWPORT_SIZE_X as usize + x as usize]
+                    == self.dmg_colors[0]
+                {
                     print!(" ");
+                } else {
+                    print!("1");
                 }
             }
 
@@ -243,3 +670,160 @@ impl Ppu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE_COLOR_1: [u8; 2] = [0xFF, 0x00];
+    const TILE_COLOR_2: [u8; 2] = [0x00, 0xFF];
+    const TILE_COLOR_3: [u8; 2] = [0xFF, 0xFF];
+
+    //Every BG and OBJ palette color is different, OBJ colors have the top bit set
+    fn bg_color(palette: u8, color: u8) -> u32 {
+        palette::rgb555((palette * 4 + color) as u16)
+    }
+
+    fn obj_color(palette: u8, color: u8) -> u32 {
+        palette::rgb555(0x4000 | (palette * 4 + color) as u16)
+    }
+
+    fn cgb_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_color_mode(ColorMode::Cgb);
+        ppu.write_registers(0xFF40, 0x80 | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE);
+
+        ppu.write_registers(0xFF68, PALETTE_AUTO_INCREMENT);
+        ppu.write_registers(0xFF6A, PALETTE_AUTO_INCREMENT);
+        for i in 0..32u16 {
+            for byte in i.to_le_bytes().iter() {
+                ppu.write_registers(0xFF69, *byte);
+            }
+            for byte in (0x4000 | i).to_le_bytes().iter() {
+                ppu.write_registers(0xFF6B, *byte);
+            }
+        }
+        ppu
+    }
+
+    //Same row all along the tile, first then second bit plane
+    fn set_tile(ppu: &mut Ppu, bank: u8, tile: u8, row: [u8; 2]) {
+        for y in 0..8 {
+            set_tile_row(ppu, bank, tile, y, row);
+        }
+    }
+
+    fn set_tile_row(ppu: &mut Ppu, bank: u8, tile: u8, y: u8, row: [u8; 2]) {
+        let addr = tile as u16 * TILE_SIZE as u16 + y as u16 * 2;
+        ppu.set_vram_bank(bank);
+        ppu.write(addr, row[0]);
+        ppu.write(addr + 1, row[1]);
+        ppu.set_vram_bank(0);
+    }
+
+    //Tile number and attributes of a BG map entry on the first row
+    fn set_bg(ppu: &mut Ppu, x: u16, tile: u8, attributes: u8) {
+        ppu.write(0x9800 - 0x8000 + x, tile);
+        ppu.set_vram_bank(1);
+        ppu.write(0x9800 - 0x8000 + x, attributes);
+        ppu.set_vram_bank(0);
+    }
+
+    fn set_sprite(ppu: &mut Ppu, index: u16, x: u8, tile: u8, attributes: u8) {
+        let entry = index * SPRITE_SIZE as u16;
+        ppu.write_oam(entry, 16);
+        ppu.write_oam(entry + 1, x + 8);
+        ppu.write_oam(entry + 2, tile);
+        ppu.write_oam(entry + 3, attributes);
+    }
+
+    fn render_line(ppu: &mut Ppu, line: u8) -> &[u32] {
+        ppu.ly = line;
+        ppu.line_gen();
+        let start = line as usize * VIEWPORT_SIZE_X as usize;
+        &ppu.frame[start..start + VIEWPORT_SIZE_X as usize]
+    }
+
+    #[test]
+    fn bg_attributes_pick_the_palette_and_the_tile_bank() {
+        let mut ppu = cgb_ppu();
+        set_tile(&mut ppu, 0, 1, TILE_COLOR_1);
+        set_tile(&mut ppu, 1, 1, TILE_COLOR_3);
+        set_bg(&mut ppu, 0, 1, 5);
+        set_bg(&mut ppu, 1, 1, 2 | ATTR_BANK);
+
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[0], bg_color(5, 1));
+        assert_eq!(line[8], bg_color(2, 3));
+        assert_eq!(line[16], bg_color(0, 0));
+    }
+
+    #[test]
+    fn bg_attributes_flip_the_tile() {
+        let mut ppu = cgb_ppu();
+        //Only the top left pixel is set
+        set_tile_row(&mut ppu, 0, 1, 0, [0x80, 0x00]);
+        set_bg(&mut ppu, 0, 1, 0);
+        set_bg(&mut ppu, 1, 1, ATTR_X_FLIP);
+        set_bg(&mut ppu, 2, 1, ATTR_Y_FLIP);
+
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[0], bg_color(0, 1));
+        assert_eq!(line[7], bg_color(0, 0));
+        assert_eq!(line[8], bg_color(0, 0));
+        assert_eq!(line[15], bg_color(0, 1));
+        assert_eq!(line[16], bg_color(0, 0));
+
+        let line = render_line(&mut ppu, 7);
+        assert_eq!(line[0], bg_color(0, 0));
+        assert_eq!(line[16], bg_color(0, 1));
+    }
+
+    #[test]
+    fn bg_priority_covers_sprites_unless_lcdc_bit_0_is_off() {
+        let mut ppu = cgb_ppu();
+        set_tile(&mut ppu, 0, 1, TILE_COLOR_1);
+        set_tile(&mut ppu, 0, 2, TILE_COLOR_2);
+        //BG color 1 with and without priority, then BG color 0 with priority
+        set_bg(&mut ppu, 0, 1, 0);
+        set_bg(&mut ppu, 1, 1, ATTR_PRIORITY);
+        set_bg(&mut ppu, 2, 0, ATTR_PRIORITY);
+        for x in 0..3 {
+            set_sprite(&mut ppu, x as u16, x * 8, 2, 3);
+        }
+
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[0], obj_color(3, 2));
+        assert_eq!(line[8], bg_color(0, 1));
+        assert_eq!(line[16], obj_color(3, 2));
+
+        //The sprite attribute alone also puts the sprite behind BG colors 1 to 3
+        set_sprite(&mut ppu, 0, 0, 2, 3 | ATTR_PRIORITY);
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[0], bg_color(0, 1));
+
+        ppu.write_registers(0xFF40, 0x80 | LCDC_OBJ_ENABLE);
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[0], obj_color(3, 2));
+        assert_eq!(line[8], obj_color(3, 2));
+    }
+
+    #[test]
+    fn opri_picks_oam_or_coordinate_priority() {
+        let mut ppu = cgb_ppu();
+        set_tile(&mut ppu, 0, 2, TILE_COLOR_3);
+        //Overlapping on pixels 4 to 7, the first in OAM is on the right
+        set_sprite(&mut ppu, 0, 4, 2, 1);
+        set_sprite(&mut ppu, 1, 0, 2, 2);
+
+        assert_eq!(ppu.read_registers(0xFF6C), 0xFE);
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[4], obj_color(1, 3));
+
+        ppu.write_registers(0xFF6C, 0x01);
+        assert_eq!(ppu.read_registers(0xFF6C), 0xFF);
+        let line = render_line(&mut ppu, 0);
+        assert_eq!(line[4], obj_color(2, 3));
+        assert_eq!(line[8], obj_color(1, 3));
+    }
+}
@@ -0,0 +1,427 @@
+use std::path::PathBuf;
+
+use crate::png;
+use crate::serial::SerialDevice;
+
+//Game Boy Printer, prints are saved as PNG files in a directory
+pub struct Printer {
+    directory: PathBuf,
+    next_index: usize,
+
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    buffer: Vec<u8>,
+    status: u8,
+    //Status packets still answered with the printing bit set
+    printing: u8,
+    //Image printed so far, continued by prints without a margin in between
+    strip: Vec<u8>,
+}
+
+//Position in a packet: magic 0x88 0x33, command, compression, length, data, checksum,
+//then the printer answers 0x81 and its status
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+const MAGIC1: u8 = 0x88;
+const MAGIC2: u8 = 0x33;
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b00000001;
+const STATUS_PRINTING: u8 = 0b00000010;
+const STATUS_FULL: u8 = 0b00000100;
+const STATUS_UNPROCESSED: u8 = 0b00001000;
+
+//Games poll the status until printing is over
+const PRINTING_STATUS_POLLS: u8 = 3;
+
+//160 pixels wide, the buffer holds up to 9 data packets of 2 tile rows
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const TILE_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 9 * 0x280;
+
+//Blank pixel rows per line feed of the margins
+const ROWS_PER_FEED: usize = 8;
+
+//Palette 0x00 behaves as the usual 0xE4
+const DEFAULT_PALETTE: u8 = 0xE4;
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+impl Printer {
+    pub fn new(directory: PathBuf) -> Self {
+        Printer {
+            directory,
+            next_index: 1,
+
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+
+            buffer: Vec::new(),
+            status: 0,
+            printing: 0,
+            strip: Vec::new(),
+        }
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.printing = 0;
+            }
+            COMMAND_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    self.decompress(&data);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                if sheets > 0 {
+                    self.print(margins >> 4, margins & 0x0F, palette);
+                }
+                self.buffer.clear();
+                self.printing = PRINTING_STATUS_POLLS;
+            }
+            _ => {}
+        }
+    }
+
+    //Runs of a byte start with 0x80 | (count - 2), literals with count - 1
+    fn decompress(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            let control = data[i];
+            i += 1;
+            if control & 0x80 > 0 {
+                let count = (control & 0x7F) as usize + 2;
+                if let Some(&byte) = data.get(i) {
+                    self.buffer.extend(std::iter::repeat(byte).take(count));
+                }
+                i += 1;
+            } else {
+                let count = control as usize + 1;
+                let end = (i + count).min(data.len());
+                self.buffer.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+        }
+    }
+
+    //A print without a margin after it is continued by the next one in the same image
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        let palette = if palette == 0 {
+            DEFAULT_PALETTE
+        } else {
+            palette
+        };
+
+        if self.strip.is_empty() {
+            self.feed(margin_before);
+        }
+
+        let tile_rows = self.buffer.len() / (TILE_SIZE * TILES_PER_ROW);
+        for y in 0..tile_rows * 8 {
+            for x in 0..WIDTH {
+                let tile = (y / 8) * TILES_PER_ROW + x / 8;
+                let line = tile * TILE_SIZE + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+                let lsb = (self.buffer[line] >> bit) & 1;
+                let msb = (self.buffer[line + 1] >> bit) & 1;
+                let color = msb << 1 | lsb;
+                let shade = (palette >> (color * 2)) & 0x03;
+                self.strip.push(SHADES[shade as usize]);
+            }
+        }
+
+        if margin_after > 0 {
+            self.feed(margin_after);
+            self.save();
+        }
+    }
+
+    fn feed(&mut self, feeds: u8) {
+        let rows = feeds as usize * ROWS_PER_FEED;
+        self.strip
+            .extend(std::iter::repeat(SHADES[0]).take(rows * WIDTH));
+    }
+
+    fn save(&mut self) {
+        let strip = std::mem::take(&mut self.strip);
+        if strip.is_empty() {
+            return;
+        }
+
+        let mut path = self
+            .directory
+            .join(format!("print-{:04}.png", self.next_index));
+        while path.exists() {
+            self.next_index += 1;
+            path = self
+                .directory
+                .join(format!("print-{:04}.png", self.next_index));
+        }
+        self.next_index += 1;
+
+        match png::write_grayscale(&path, WIDTH, strip.len() / WIDTH, &strip) {
+            Ok(()) => println!("Printed {}", path.display()),
+            Err(e) => println!("Unable to save print {}: {}", path.display(), e),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.printing > 0 {
+            status |= STATUS_PRINTING;
+        }
+        if self.buffer.len() >= BUFFER_SIZE {
+            status |= STATUS_FULL;
+        }
+        if !self.buffer.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+        status
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == MAGIC1 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == MAGIC2 => State::Command,
+            //Another 0x88 may start the packet over
+            State::Magic2 if byte == MAGIC1 => State::Magic2,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 > 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length > 0 {
+                    State::Data
+                } else {
+                    State::ChecksumLow
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                self.process_packet();
+                State::Status
+            }
+            State::Status => {
+                reply = self.status();
+                if self.command == COMMAND_STATUS && self.printing > 0 {
+                    self.printing -= 1;
+                }
+                State::Magic1
+            }
+        };
+        reply
+    }
+}
+
+//A strip still waiting for its bottom margin is saved as is
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNCOMPRESSED: u8 = 0x00;
+    const COMPRESSED: u8 = 0x01;
+
+    //Nothing is saved as long as the strip is taken before the printer is dropped
+    fn printer() -> Printer {
+        Printer::new(PathBuf::new())
+    }
+
+    fn packet(command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![command, compression, length[0], length[1]];
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+
+        let mut bytes = vec![MAGIC1, MAGIC2];
+        bytes.extend(packet);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        bytes
+    }
+
+    //Alive byte and status the printer answers with
+    fn send(printer: &mut Printer, bytes: &[u8]) -> (u8, u8) {
+        let replies: Vec<u8> = bytes.iter().map(|byte| printer.exchange(*byte)).collect();
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    #[test]
+    fn checksum_is_checked() {
+        let mut printer = printer();
+        assert_eq!(
+            send(&mut printer, &packet(COMMAND_INIT, 0, &[])),
+            (ALIVE, 0)
+        );
+
+        let mut bytes = packet(COMMAND_DATA, UNCOMPRESSED, &[0x12, 0x34]);
+        let checksum_low = bytes.len() - 4;
+        bytes[checksum_low] ^= 0x01;
+        assert_eq!(send(&mut printer, &bytes), (ALIVE, STATUS_CHECKSUM_ERROR));
+        assert!(printer.buffer.is_empty());
+
+        let bytes = packet(COMMAND_DATA, UNCOMPRESSED, &[0x12, 0x34]);
+        assert_eq!(send(&mut printer, &bytes), (ALIVE, STATUS_UNPROCESSED));
+        assert_eq!(printer.buffer, vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn repeated_magic_keeps_the_packet() {
+        let mut printer = printer();
+        let mut bytes = vec![MAGIC1];
+        bytes.extend(packet(COMMAND_DATA, UNCOMPRESSED, &[0x56]));
+        assert_eq!(send(&mut printer, &bytes), (ALIVE, STATUS_UNPROCESSED));
+        assert_eq!(printer.buffer, vec![0x56]);
+    }
+
+    #[test]
+    fn compressed_data_expands() {
+        let mut printer = printer();
+        //A run of 3 0xAA then 2 literal bytes
+        let data = [0x81, 0xAA, 0x01, 0x12, 0x34];
+        send(&mut printer, &packet(COMMAND_DATA, COMPRESSED, &data));
+        assert_eq!(printer.buffer, vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn print_applies_the_palette_and_margins() {
+        let mut printer = printer();
+        //A tile row, the first tile in color 1 and the others in color 0
+        let mut data = vec![0x00; TILES_PER_ROW * TILE_SIZE];
+        for row in 0..8 {
+            data[row * 2] = 0xFF;
+        }
+        send(&mut printer, &packet(COMMAND_DATA, UNCOMPRESSED, &data));
+
+        //1 sheet, 1 line feed before and none after, inverted palette
+        let (_, status) = send(
+            &mut printer,
+            &packet(COMMAND_PRINT, 0, &[1, 0x10, 0x1B, 0x40]),
+        );
+        assert_eq!(status & STATUS_PRINTING, STATUS_PRINTING);
+        assert!(printer.buffer.is_empty());
+
+        let strip = std::mem::take(&mut printer.strip);
+        assert_eq!(strip.len(), (ROWS_PER_FEED + 8) * WIDTH);
+        assert!(strip[..ROWS_PER_FEED * WIDTH]
+            .iter()
+            .all(|&shade| shade == 0xFF));
+        let image = &strip[ROWS_PER_FEED * WIDTH..];
+        assert_eq!(image[0], 0x55);
+        assert_eq!(image[7 * WIDTH + 7], 0x55);
+        assert_eq!(image[8], 0x00);
+        assert_eq!(image[7 * WIDTH + WIDTH - 1], 0x00);
+
+        //Printing is reported for a few status polls
+        let statuses: Vec<u8> = (0..PRINTING_STATUS_POLLS + 1)
+            .map(|_| send(&mut printer, &packet(COMMAND_STATUS, 0, &[])).1)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![STATUS_PRINTING, STATUS_PRINTING, STATUS_PRINTING, 0]
+        );
+    }
+
+    #[test]
+    fn palette_0_is_the_default_one() {
+        let mut printer = printer();
+        let mut data = vec![0x00; TILES_PER_ROW * TILE_SIZE];
+        data[0] = 0xFF;
+        send(&mut printer, &packet(COMMAND_DATA, UNCOMPRESSED, &data));
+        send(
+            &mut printer,
+            &packet(COMMAND_PRINT, 0, &[1, 0x00, 0x00, 0x40]),
+        );
+
+        let strip = std::mem::take(&mut printer.strip);
+        assert_eq!(strip.len(), 8 * WIDTH);
+        assert_eq!(strip[0], SHADES[1]);
+        assert_eq!(strip[8], SHADES[0]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::model::Model;
+
 #[allow(non_snake_case)]
 pub struct Registers {
     pub A: u8,
@@ -37,6 +39,34 @@ impl Registers {
         }
     }
 
+    //State left by the boot ROM of each model
+    pub fn after_boot(model: Model, header_checksum: u8) -> Self {
+        //The DMG and MGB boot ROMs leave H and C set by the header checksum
+        let dmg_flags = if header_checksum != 0 { 0xB0 } else { 0x80 };
+
+        let (A, F, B, C, D, E, H, L) = match model {
+            Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg => (0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::Agb => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+
+        Registers {
+            A,
+            B,
+            C,
+            D,
+            E,
+            H,
+            L,
+            F,
+            SP: 0xFFFE,
+            PC: 0x0100,
+        }
+    }
+
     //Getters
     pub fn AF(&self) -> u16 {
         //AF returns only A
@@ -0,0 +1,92 @@
+extern crate flate2;
+extern crate zip;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+
+use flate2::read::GzDecoder;
+
+use crate::cartridge::{patch, CartridgeError};
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+//Read a ROM file, plain or inside a .zip/.gz archive
+pub fn load(path: &path::Path) -> Result<Vec<u8>, CartridgeError> {
+    let data = fs::read(path)?;
+
+    match extension(path).as_ref().map(String::as_str) {
+        Some("zip") => load_zip(data),
+        Some("gz") => load_gz(&data),
+        _ => Ok(data),
+    }
+}
+
+//Apply the given patch, or the first .ips/.ups/.bps next to the ROM
+pub fn patch(
+    rom: Vec<u8>,
+    rom_path: &path::Path,
+    patch_path: Option<&path::Path>,
+) -> Result<Vec<u8>, CartridgeError> {
+    let patch_path = match patch_path {
+        Some(patch_path) => patch_path.to_path_buf(),
+        None => match find_patch(rom_path) {
+            Some(patch_path) => patch_path,
+            None => return Ok(rom),
+        },
+    };
+
+    println!("Applying patch {}", patch_path.display());
+    let data = fs::read(&patch_path)?;
+    Ok(patch::apply(&rom, &data)?)
+}
+
+fn find_patch(rom_path: &path::Path) -> Option<path::PathBuf> {
+    //Game.gb.gz is patched by Game.ips like Game.gb, as for its save file
+    let rom_path = match rom_path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("gz") => rom_path.with_extension(""),
+        _ => rom_path.to_path_buf(),
+    };
+
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|patch_path| patch_path.is_file())
+}
+
+fn load_gz(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut rom = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut rom)?;
+
+    Ok(rom)
+}
+
+//First .gb/.gbc entry of the archive
+fn load_zip(data: Vec<u8>) -> Result<Vec<u8>, CartridgeError> {
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(data)).map_err(io::Error::from)?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(io::Error::from)?;
+        match extension(path::Path::new(file.name()))
+            .as_ref()
+            .map(String::as_str)
+        {
+            Some("gb") | Some("gbc") => {
+                let mut rom = Vec::new();
+                file.read_to_end(&mut rom)?;
+                return Ok(rom);
+            }
+            _ => {}
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "no .gb or .gbc file in archive").into())
+}
+
+fn extension(path: &path::Path) -> Option<String> {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+}
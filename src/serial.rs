@@ -0,0 +1,192 @@
+use crate::link::{Message, Transport, QUANTUM_CYCLES};
+
+//Serial port, SB (0xFF01) and SC (0xFF02)
+pub struct Serial {
+    data: u8,
+    transfer: bool,
+    internal_clock: bool,
+
+    bits_left: u8,
+    div_bit: bool,
+    //Byte being sent, captured once it is out
+    sending: u8,
+    output: Vec<u8>,
+
+    link: Option<Link>,
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+//Accessory clocked by the Game Boy, like the printer
+pub trait SerialDevice {
+    //Takes the byte sent and returns the one shifted back in
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+//Link cable to another Game Boy, see link.rs for the lockstep protocol
+struct Link {
+    transport: Transport,
+    cycles: u32,
+    outgoing: Message,
+    awaiting_reply: bool,
+}
+
+//The internal clock shifts a bit on the falling edge of this DIV bit, 8192 Hz
+const SERIAL_CLOCK_DIV_BIT: u16 = 1 << 8;
+
+//Nothing is connected, the input line stays high. Devices answer once the byte is out
+const DISCONNECTED_INPUT: u8 = 1;
+const DISCONNECTED_BYTE: u8 = 0xFF;
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            transfer: false,
+            internal_clock: false,
+
+            bits_left: 0,
+            div_bit: false,
+            sending: 0,
+            output: Vec::new(),
+
+            link: None,
+            device: None,
+        }
+    }
+
+    pub fn attach_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.link = None;
+        self.device = Some(device);
+    }
+
+    pub fn connect(&mut self, transport: Transport) {
+        self.device = None;
+        self.link = Some(Link {
+            transport,
+            cycles: 0,
+            outgoing: Message::default(),
+            awaiting_reply: false,
+        });
+    }
+
+    //Returns true when a transfer completes and the serial interrupt is requested
+    pub fn do_cycle(&mut self, div: u16) -> bool {
+        let div_bit = div & SERIAL_CLOCK_DIV_BIT > 0;
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+
+        if self.link.is_some() {
+            return self.link_cycle();
+        }
+
+        //With the external clock and no other side, the transfer never ends
+        if !falling_edge || !self.transfer || !self.internal_clock {
+            return false;
+        }
+
+        self.data = self.data << 1 | DISCONNECTED_INPUT;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            return false;
+        }
+
+        if let Some(device) = self.device.as_mut() {
+            self.data = device.exchange(self.sending);
+        }
+        self.transfer = false;
+        self.output.push(self.sending);
+        true
+    }
+
+    //Swaps messages with the other end once per quantum, transfers complete on these boundaries
+    fn link_cycle(&mut self) -> bool {
+        let mut link = match self.link.take() {
+            Some(link) => link,
+            None => return false,
+        };
+
+        link.cycles += 1;
+        if link.cycles < QUANTUM_CYCLES {
+            self.link = Some(link);
+            return false;
+        }
+        link.cycles = 0;
+
+        let outgoing = std::mem::take(&mut link.outgoing);
+        let incoming = match link
+            .transport
+            .send(outgoing)
+            .and_then(|_| link.transport.receive())
+        {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                println!("Link cable disconnected: {}", e);
+                if link.awaiting_reply {
+                    self.complete(DISCONNECTED_BYTE, self.sending);
+                    return true;
+                }
+                return false;
+            }
+        };
+
+        let mut interrupt = false;
+        if let Some(reply) = incoming.reply {
+            if link.awaiting_reply {
+                link.awaiting_reply = false;
+                self.complete(reply, self.sending);
+                interrupt = true;
+            }
+        }
+
+        //Only a side waiting on the external clock takes the byte, the other gets nothing back
+        if let Some(byte) = incoming.transfer {
+            if self.transfer && !self.internal_clock {
+                link.outgoing.reply = Some(self.data);
+                self.complete(byte, self.data);
+                interrupt = true;
+            } else {
+                link.outgoing.reply = Some(DISCONNECTED_BYTE);
+            }
+        }
+
+        self.link = Some(link);
+        interrupt
+    }
+
+    fn complete(&mut self, received: u8, sent: u8) {
+        self.data = received;
+        self.transfer = false;
+        self.output.push(sent);
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.data,
+            _ => (self.transfer as u8) << 7 | 0x7E | self.internal_clock as u8,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF01 => self.data = data,
+            _ => {
+                self.transfer = data & 0b10000000 > 0;
+                self.internal_clock = data & 0b00000001 > 0;
+                self.bits_left = 8;
+                self.sending = self.data;
+
+                if let Some(link) = self.link.as_mut() {
+                    link.awaiting_reply = self.transfer && self.internal_clock;
+                    if link.awaiting_reply {
+                        link.outgoing.transfer = Some(self.data);
+                    }
+                }
+            }
+        }
+    }
+
+    //Bytes sent since the last call, test ROMs print their results this way
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.drain(..).collect()
+    }
+}
@@ -0,0 +1,81 @@
+use crate::palette;
+
+//SNES picture around the Game Boy screen: 4bpp tiles from CHR_TRN, map and palettes from PCT_TRN
+pub struct Border {
+    tiles: Vec<u8>,
+    map: Vec<u16>,
+    palettes: [[u16; 16]; 4],
+}
+
+const TILES: usize = 256;
+const TILE_SIZE: usize = 32;
+const HALF_TILES_SIZE: usize = TILES / 2 * TILE_SIZE;
+
+const MAP_WIDTH: usize = 32;
+const MAP_HEIGHT: usize = 28;
+const MAP_SIZE: usize = 0x800;
+//Border palettes are SNES palettes 4 to 7
+const PALETTES_SIZE: usize = 4 * 16 * 2;
+
+const MAP_TILE: u16 = 0x00FF;
+const MAP_X_FLIP: u16 = 0x4000;
+const MAP_Y_FLIP: u16 = 0x8000;
+
+impl Border {
+    pub fn new() -> Self {
+        Border {
+            tiles: vec![0; TILES * TILE_SIZE],
+            map: vec![0; MAP_WIDTH * MAP_WIDTH],
+            palettes: [[0; 16]; 4],
+        }
+    }
+
+    //CHR_TRN sends tiles 0x00-0x7F or 0x80-0xFF
+    pub fn set_tiles(&mut self, half: usize, data: &[u8]) {
+        let start = half * HALF_TILES_SIZE;
+        self.tiles[start..start + HALF_TILES_SIZE].copy_from_slice(&data[..HALF_TILES_SIZE]);
+    }
+
+    pub fn set_map(&mut self, data: &[u8]) {
+        for (entry, bytes) in self.map.iter_mut().zip(data[..MAP_SIZE].chunks(2)) {
+            *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        let colors = data[MAP_SIZE..MAP_SIZE + PALETTES_SIZE].chunks(2);
+        for (i, color) in colors.enumerate() {
+            self.palettes[i / 16][i % 16] = u16::from_le_bytes([color[0], color[1]]);
+        }
+    }
+
+    //Color 0 is transparent and shows the backdrop
+    pub fn render(&self, frame: &mut [u32], width: usize, backdrop: u32) {
+        for tile_y in 0..MAP_HEIGHT {
+            for tile_x in 0..MAP_WIDTH {
+                let entry = self.map[tile_y * MAP_WIDTH + tile_x];
+                let tile = (entry & MAP_TILE) as usize * TILE_SIZE;
+                let palette = &self.palettes[((entry >> 10) & 0x03) as usize];
+
+                for row in 0..8 {
+                    let y = if entry & MAP_Y_FLIP > 0 { 7 - row } else { row };
+                    let planes = [
+                        self.tiles[tile + y * 2],
+                        self.tiles[tile + y * 2 + 1],
+                        self.tiles[tile + 16 + y * 2],
+                        self.tiles[tile + 16 + y * 2 + 1],
+                    ];
+
+                    for col in 0..8 {
+                        let x = if entry & MAP_X_FLIP > 0 { col } else { 7 - col };
+                        let color = planes.iter().enumerate().fold(0, |color, (plane, bits)| {
+                            color | ((bits >> x) & 0x01) << plane
+                        });
+
+                        frame[(tile_y * 8 + row) * width + tile_x * 8 + col] = match color {
+                            0 => backdrop,
+                            _ => palette::rgb555(palette[color as usize]),
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
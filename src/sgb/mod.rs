@@ -0,0 +1,553 @@
+mod border;
+
+use crate::palette;
+use border::Border;
+
+//Super Game Boy, commands sent as packets over P1 (0xFF00) and a colorized screen with a border
+pub struct Sgb {
+    //Games without SGB support in the header are ignored, as the SGB BIOS does
+    enabled: bool,
+
+    //Packet being received, bits LSB first, the command spans up to 7 packets
+    packet: [u8; PACKET_SIZE],
+    bits: usize,
+    receiving: bool,
+    select: u8,
+    command: Vec<u8>,
+
+    //Color 0 of palette 0 is shared by the 4 palettes
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    //Palette of each 8x8 block of the screen
+    attributes: [u8; ATTRIBUTE_BLOCKS],
+    attribute_files: Vec<[u8; ATTRIBUTE_BLOCKS]>,
+    mask: Mask,
+    transfer: Option<Transfer>,
+    border: Border,
+
+    players: u8,
+    player: u8,
+
+    screen: Vec<u32>,
+    frame: Vec<u32>,
+}
+
+//The Game Boy screen sits in the middle of the border
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 224;
+const GB_WIDTH: usize = 160;
+const GB_HEIGHT: usize = 144;
+const GB_LEFT: usize = 48;
+const GB_TOP: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+const ATTRIBUTE_WIDTH: usize = GB_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = GB_HEIGHT / 8;
+const ATTRIBUTE_BLOCKS: usize = ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_BLOCKS / 4;
+const SYSTEM_PALETTES: usize = 512;
+
+//Bytes a VRAM transfer reads from the screen, 256 tiles laid out 20 per row
+pub const TRANSFER_SIZE: usize = 0x1000;
+
+//Cartridge header bytes a game supporting the SGB sets
+pub const SGB_FLAG_ADDR: u16 = 0x146;
+pub const OLD_LICENSEE_ADDR: u16 = 0x14B;
+
+//Grays until the game picks its colors
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+//Commands, first byte of the first packet with the number of packets in the low bits
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+//MASK_EN, what shows instead of the game screen
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mask {
+    Off,
+    Freeze,
+    Black,
+    Color0,
+}
+
+//VRAM transfers send the next frame to the SGB
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+pub fn supports_sgb(sgb_flag: u8, old_licensee: u8) -> bool {
+    sgb_flag == 0x03 && old_licensee == 0x33
+}
+
+impl Sgb {
+    pub fn new(enabled: bool) -> Self {
+        Sgb {
+            enabled,
+
+            packet: [0; PACKET_SIZE],
+            bits: 0,
+            receiving: false,
+            select: 0x30,
+            command: Vec::new(),
+
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![DEFAULT_PALETTE; SYSTEM_PALETTES],
+            attributes: [0; ATTRIBUTE_BLOCKS],
+            attribute_files: vec![[0; ATTRIBUTE_BLOCKS]; ATTRIBUTE_FILES],
+            mask: Mask::Off,
+            transfer: None,
+            border: Border::new(),
+
+            players: 1,
+            player: 0,
+
+            screen: vec![0; GB_WIDTH * GB_HEIGHT],
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    //P1 with both lines high reads the current joypad, 0xF for player 1 down to 0xC for player 4
+    pub fn joypad_id(&self) -> u8 {
+        0x0F - self.player
+    }
+
+    //P14 and P15 pulses: both low resets, then P14 low sends a 0 and P15 low a 1, high in between
+    pub fn write_joypad(&mut self, select: u8) {
+        let previous = self.select;
+        self.select = select;
+
+        match select {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.bits = 0;
+                self.receiving = true;
+            }
+            0x10 | 0x20 if previous == 0x30 && self.receiving => {
+                let bit = select == 0x10;
+                if self.bits == PACKET_BITS {
+                    //A stop bit of 0 ends the packet
+                    self.receiving = false;
+                    if !bit {
+                        self.packet_received();
+                    }
+                } else {
+                    if bit {
+                        self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+                    }
+                    self.bits += 1;
+                }
+            }
+            //Going back up after reading the buttons moves to the next joypad
+            0x30 if previous == 0x10 && !self.receiving && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            _ => {}
+        }
+    }
+
+    fn packet_received(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+            return;
+        }
+        self.command.extend_from_slice(&self.packet);
+
+        let packets = (self.command[0] & 0x07) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_division(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => self.set_system_palettes(data),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => self.transfer = Some(Transfer::Tiles((data[1] & 0x01) as usize)),
+            PCT_TRN => self.transfer = Some(Transfer::Border),
+            ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+            ATTR_SET => self.set_attribute_file(data[1]),
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::Off,
+                }
+            }
+            //Sound, SNES code and the like do nothing on the Game Boy side
+            _ => {}
+        }
+    }
+
+    //Shared color 0, then colors 1 to 3 of both palettes
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]);
+        self.palettes[0][0] = color(0);
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    //Rectangles in blocks, each can color its inside, its border and what is outside
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let sets = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks(6).take(sets) {
+            if set.len() < 6 {
+                break;
+            }
+
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let on_border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            //Changing only the inside or the outside takes the border along
+            let (control, on_border) = match control {
+                0b001 => (0b011, inside),
+                0b100 => (0b110, outside),
+                _ => (control, on_border),
+            };
+
+            let (x1, y1, x2, y2) = (set[2] & 0x1F, set[3] & 0x1F, set[4] & 0x1F, set[5] & 0x1F);
+            for y in 0..ATTRIBUTE_HEIGHT as u8 {
+                for x in 0..ATTRIBUTE_WIDTH as u8 {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if edge {
+                        (control & 0b010 > 0, on_border)
+                    } else if within {
+                        (control & 0b001 > 0, inside)
+                    } else {
+                        (control & 0b100 > 0, outside)
+                    };
+                    if let (true, palette) = palette {
+                        self.attributes[y as usize * ATTRIBUTE_WIDTH + x as usize] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    //Whole rows or columns, bit 7 for a row
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let lines = (data[1] as usize).min(data.len() - 2);
+        for &line in &data[2..2 + lines] {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 > 0 {
+                if number < ATTRIBUTE_HEIGHT {
+                    for x in 0..ATTRIBUTE_WIDTH {
+                        self.attributes[number * ATTRIBUTE_WIDTH + x] = palette;
+                    }
+                }
+            } else if number < ATTRIBUTE_WIDTH {
+                for y in 0..ATTRIBUTE_HEIGHT {
+                    self.attributes[y * ATTRIBUTE_WIDTH + number] = palette;
+                }
+            }
+        }
+    }
+
+    //The screen split on a row or column, with palettes before, on and after the line
+    fn attribute_division(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 > 0;
+        let line = (data[2] & 0x1F) as usize;
+
+        for y in 0..ATTRIBUTE_HEIGHT {
+            for x in 0..ATTRIBUTE_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTRIBUTE_WIDTH + x] = match position {
+                    p if p < line => before,
+                    p if p == line => on_line,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    //Palettes of single blocks from a starting block, 4 per byte from the high bits
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let mut x = (data[1] & 0x1F) as usize;
+        let mut y = (data[2] & 0x1F) as usize;
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 > 0;
+
+        for i in 0..count.min(ATTRIBUTE_BLOCKS) {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            if x >= ATTRIBUTE_WIDTH || y >= ATTRIBUTE_HEIGHT {
+                break;
+            }
+            self.attributes[y * ATTRIBUTE_WIDTH + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == ATTRIBUTE_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTRIBUTE_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    //The 4 palettes from those sent with PAL_TRN, optionally with an attribute file
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let number = u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize;
+            self.palettes[i] = self.system_palettes[number % SYSTEM_PALETTES];
+        }
+        //The shared color 0 is the one of palette 0
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+
+        if data[9] & 0x80 > 0 {
+            self.set_attribute_file(data[9]);
+        } else if data[9] & 0x40 > 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    //Bits 0 to 5 select the file, bit 6 cancels the mask
+    fn set_attribute_file(&mut self, data: u8) {
+        let file = (data & 0x3F) as usize;
+        if file < ATTRIBUTE_FILES {
+            self.attributes = self.attribute_files[file];
+        }
+        if data & 0x40 > 0 {
+            self.mask = Mask::Off;
+        }
+    }
+
+    fn receive_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (i, color) in colors.chunks(2).enumerate() {
+                        palette[i] = u16::from_le_bytes([color[0], color[1]]);
+                    }
+                }
+            }
+            Transfer::Tiles(half) => self.border.set_tiles(half, data),
+            Transfer::Border => self.border.set_map(data),
+            Transfer::Attributes => {
+                for (file, bytes) in self
+                    .attribute_files
+                    .iter_mut()
+                    .zip(data.chunks(ATTRIBUTE_FILE_SIZE))
+                {
+                    for (i, block) in file.iter_mut().enumerate() {
+                        *block = (bytes[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+                    }
+                }
+            }
+        }
+    }
+
+    //Takes the shades of a Game Boy frame and returns the picture on the TV
+    pub fn end_frame(&mut self, shades: &[u8]) -> &[u32] {
+        if let Some(transfer) = self.transfer.take() {
+            let data = transfer_data(shades);
+            self.receive_transfer(transfer, &data);
+        }
+
+        let color0 = palette::rgb555(self.palettes[0][0]);
+        match self.mask {
+            Mask::Off => {
+                for (i, pixel) in self.screen.iter_mut().enumerate() {
+                    let block = (i / GB_WIDTH / 8) * ATTRIBUTE_WIDTH + (i % GB_WIDTH) / 8;
+                    let shade = shades[i] as usize;
+                    let color = match shade {
+                        0 => self.palettes[0][0],
+                        _ => self.palettes[self.attributes[block] as usize][shade],
+                    };
+                    *pixel = palette::rgb555(color);
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => self.screen.iter_mut().for_each(|pixel| *pixel = 0),
+            Mask::Color0 => self.screen.iter_mut().for_each(|pixel| *pixel = color0),
+        }
+
+        self.border.render(&mut self.frame, SCREEN_WIDTH, color0);
+        for y in 0..GB_HEIGHT {
+            let start = (GB_TOP + y) * SCREEN_WIDTH + GB_LEFT;
+            self.frame[start..start + GB_WIDTH]
+                .copy_from_slice(&self.screen[y * GB_WIDTH..(y + 1) * GB_WIDTH]);
+        }
+
+        &self.frame
+    }
+}
+
+//Tiles read back from the shades on screen, the game shows tiles 0 to 255 in order
+fn transfer_data(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_mut(16).enumerate() {
+        let left = (tile % ATTRIBUTE_WIDTH) * 8;
+        let top = (tile / ATTRIBUTE_WIDTH) * 8;
+        for row in 0..8 {
+            for x in 0..8 {
+                let shade = shades[(top + row) * GB_WIDTH + left + x];
+                bytes[row * 2] |= (shade & 0x01) << (7 - x);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - x);
+            }
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Reset pulse, 128 bits LSB first and the 0 stop bit, P1 back high after each pulse
+    fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for bit in 0..PACKET_BITS {
+            let one = packet[bit / 8] & (1 << (bit % 8)) > 0;
+            sgb.write_joypad(if one { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    fn send_command(sgb: &mut Sgb, command: &[u8]) {
+        let packets = (command[0] & 0x07) as usize;
+        let mut data = command.to_vec();
+        data.resize(packets * PACKET_SIZE, 0);
+        for packet in data.chunks(PACKET_SIZE) {
+            send_packet(sgb, packet);
+        }
+    }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attributes[y * ATTRIBUTE_WIDTH + x]
+    }
+
+    #[test]
+    fn pal01_sets_palettes_0_and_1() {
+        let mut sgb = Sgb::new(true);
+        let colors: [u16; 7] = [0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777];
+        let mut command = vec![PAL01 << 3 | 1];
+        for color in colors.iter() {
+            command.extend_from_slice(&color.to_le_bytes());
+        }
+        send_command(&mut sgb, &command);
+
+        assert_eq!(sgb.palettes[0], [0x1111, 0x2222, 0x3333, 0x4444]);
+        //Color 0 is shared, only palette 0 holds it
+        assert_eq!(sgb.palettes[1][1..], [0x5555, 0x6666, 0x7777]);
+        assert_eq!(sgb.palettes[2], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn packets_are_ignored_without_sgb_support() {
+        let mut sgb = Sgb::new(false);
+        send_command(&mut sgb, &[PAL01 << 3 | 1, 0x11, 0x11]);
+        assert_eq!(sgb.palettes[0], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn attr_blk_over_2_packets() {
+        let mut sgb = Sgb::new(true);
+        #[rustfmt::skip]
+        send_command(&mut sgb, &[
+            ATTR_BLK << 3 | 2, 3,
+            //Inside 1, border 2 and outside 3
+            0b111, 0x39, 1, 1, 3, 3,
+            //Inside only, the border follows it
+            0b001, 0x00, 10, 10, 12, 12,
+            //Outside only of the whole screen, only its border changes
+            0b100, 0x10, 0, 0, 19, 17,
+        ]);
+
+        assert_eq!(attribute(&sgb, 2, 2), 1);
+        assert_eq!(attribute(&sgb, 1, 1), 2);
+        assert_eq!(attribute(&sgb, 5, 5), 3);
+        assert_eq!(attribute(&sgb, 10, 10), 0);
+        assert_eq!(attribute(&sgb, 11, 11), 0);
+        assert_eq!(attribute(&sgb, 0, 0), 1);
+        assert_eq!(attribute(&sgb, 19, 17), 1);
+    }
+
+    #[test]
+    fn mlt_req_cycles_the_joypads() {
+        let mut sgb = Sgb::new(true);
+        let next_joypad = |sgb: &mut Sgb| {
+            sgb.write_joypad(0x10);
+            sgb.write_joypad(0x30);
+            sgb.joypad_id()
+        };
+        assert_eq!(sgb.joypad_id(), 0x0F);
+        assert_eq!(next_joypad(&mut sgb), 0x0F);
+
+        send_command(&mut sgb, &[MLT_REQ << 3 | 1, 0x01]);
+        assert_eq!(sgb.joypad_id(), 0x0F);
+        assert_eq!(next_joypad(&mut sgb), 0x0E);
+        assert_eq!(next_joypad(&mut sgb), 0x0F);
+
+        send_command(&mut sgb, &[MLT_REQ << 3 | 1, 0x03]);
+        let ids: Vec<u8> = (0..4).map(|_| next_joypad(&mut sgb)).collect();
+        assert_eq!(ids, vec![0x0E, 0x0D, 0x0C, 0x0F]);
+
+        send_command(&mut sgb, &[MLT_REQ << 3 | 1, 0x00]);
+        assert_eq!(next_joypad(&mut sgb), 0x0F);
+    }
+}
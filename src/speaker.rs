@@ -0,0 +1,160 @@
+extern crate cpal;
+
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::audio::AudioSink;
+
+//Buffered audio, the pacer keeps it around half full
+const LATENCY_SECONDS: f64 = 0.1;
+
+//Plays samples on the default output device through a ring buffer drained by the device thread
+pub struct Speaker {
+    _stream: cpal::Stream,
+    buffer: Arc<Mutex<RingBuffer>>,
+    sample_rate: u32,
+    reported_underruns: u64,
+}
+
+struct RingBuffer {
+    samples: Vec<f32>,
+    read: usize,
+    len: usize,
+    //Playback starts once half full, underruns before that are not counted
+    started: bool,
+    underruns: u64,
+}
+
+impl Speaker {
+    pub fn new() -> Result<Self, String> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or_else(|| "no output device".to_string())?;
+        let config = device
+            .default_output_config()
+            .map_err(|e| e.to_string())?
+            .config();
+
+        let sample_rate = config.sample_rate.0;
+        let channels = config.channels as usize;
+        let capacity = (sample_rate as f64 * LATENCY_SECONDS) as usize * 2;
+        let buffer = Arc::new(Mutex::new(RingBuffer::new(capacity)));
+
+        let device_buffer = buffer.clone();
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    if let Ok(mut buffer) = device_buffer.lock() {
+                        buffer.fill(data, channels);
+                    }
+                },
+                |e| eprintln!("Audio stream error: {}", e),
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(Speaker {
+            _stream: stream,
+            buffer,
+            sample_rate,
+            reported_underruns: 0,
+        })
+    }
+
+    //Buffer fill level between 0 and 1, for the pacer to stay in sync with the device
+    pub fn level_monitor(&self) -> impl Fn() -> f64 {
+        let buffer = self.buffer.clone();
+        move || match buffer.lock() {
+            Ok(buffer) => buffer.len as f64 / buffer.samples.len() as f64,
+            Err(_) => 0.0,
+        }
+    }
+}
+
+impl AudioSink for Speaker {
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn write(&mut self, samples: &[f32]) {
+        let underruns = match self.buffer.lock() {
+            Ok(mut buffer) => {
+                buffer.push(samples);
+                buffer.underruns
+            }
+            Err(_) => return,
+        };
+
+        if underruns > self.reported_underruns {
+            println!("Audio underrun ({} total)", underruns);
+            self.reported_underruns = underruns;
+        }
+    }
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        RingBuffer {
+            samples: vec![0.0; capacity],
+            read: 0,
+            len: 0,
+            started: false,
+            underruns: 0,
+        }
+    }
+
+    //Samples that do not fit are dropped, as when fast forwarding
+    fn push(&mut self, samples: &[f32]) {
+        let capacity = self.samples.len();
+        for &sample in samples.iter().take(capacity - self.len) {
+            self.samples[(self.read + self.len) % capacity] = sample;
+            self.len += 1;
+        }
+
+        if self.len >= capacity / 2 {
+            self.started = true;
+        }
+    }
+
+    fn pop(&mut self) -> f32 {
+        let sample = self.samples[self.read];
+        self.read = (self.read + 1) % self.samples.len();
+        self.len -= 1;
+        sample
+    }
+
+    //Fills a device buffer, stereo is downmixed to mono or padded with silence for more channels
+    fn fill(&mut self, data: &mut [f32], channels: usize) {
+        let mut underrun = false;
+        for frame in data.chunks_mut(channels) {
+            let (left, right) = if self.started && self.len >= 2 {
+                (self.pop(), self.pop())
+            } else {
+                underrun |= self.started;
+                (0.0, 0.0)
+            };
+
+            match frame {
+                [mono] => *mono = (left + right) / 2.0,
+                [l, r, rest @ ..] => {
+                    *l = left;
+                    *r = right;
+                    for sample in rest {
+                        *sample = 0.0;
+                    }
+                }
+                [] => {}
+            }
+        }
+
+        //Wait for the buffer to refill before playing again
+        if underrun {
+            self.underruns += 1;
+            self.started = false;
+        }
+    }
+}
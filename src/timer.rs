@@ -0,0 +1,101 @@
+//Timer, TIMA (0xFF05), TMA (0xFF06) and TAC (0xFF07), clocked from DIV so it follows the CPU speed
+pub struct Timer {
+    counter: u8,
+    modulo: u8,
+    control: u8,
+
+    div_bit: bool,
+}
+
+const TAC_ENABLE_MASK: u8 = 0b00000100;
+const TAC_CLOCK_MASK: u8 = 0b00000011;
+
+//TIMA counts on the falling edge of a DIV bit: 4096, 262144, 65536 or 16384 Hz
+const CLOCK_DIV_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: 0,
+            modulo: 0,
+            control: 0,
+
+            div_bit: false,
+        }
+    }
+
+    //Returns true when TIMA overflows and the timer interrupt is requested
+    pub fn do_cycle(&mut self, div: u16) -> bool {
+        let enabled = self.control & TAC_ENABLE_MASK > 0;
+        let div_bit = enabled && div & CLOCK_DIV_BITS[(self.control & TAC_CLOCK_MASK) as usize] > 0;
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+
+        if !falling_edge {
+            return false;
+        }
+
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = if overflow { self.modulo } else { counter };
+        overflow
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF05 => self.counter,
+            0xFF06 => self.modulo,
+            _ => 0xF8 | self.control,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF05 => self.counter = data,
+            0xFF06 => self.modulo = data,
+            _ => self.control = data & (TAC_ENABLE_MASK | TAC_CLOCK_MASK),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Runs the timer from DIV 0, returns the cycles where it overflowed
+    fn run(timer: &mut Timer, cycles: u16) -> Vec<u16> {
+        (1..=cycles).filter(|div| timer.do_cycle(*div)).collect()
+    }
+
+    #[test]
+    fn counts_at_the_selected_rate() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0b101);
+        run(&mut timer, 16 * 10);
+        assert_eq!(timer.read(0xFF05), 10);
+
+        timer.write(0xFF07, 0b100);
+        timer.write(0xFF05, 0);
+        run(&mut timer, 1024 * 3);
+        assert_eq!(timer.read(0xFF05), 3);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_and_requests_the_interrupt() {
+        let mut timer = Timer::new();
+        timer.write(0xFF05, 0xFE);
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF07, 0b101);
+
+        assert_eq!(run(&mut timer, 16 * 3), vec![32]);
+        assert_eq!(timer.read(0xFF05), 0x81);
+    }
+
+    #[test]
+    fn stopped_when_disabled() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0b001);
+        run(&mut timer, 1024);
+        assert_eq!(timer.read(0xFF05), 0);
+        assert_eq!(timer.read(0xFF07), 0xF9);
+    }
+}